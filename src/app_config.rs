@@ -1,31 +1,126 @@
 use std::collections::HashMap;
 
+use std::io::Error;
+use std::io::ErrorKind;
+
 use yaml_rust::Yaml;
 use yaml_rust::YamlLoader;
 
 use crate::AppResult;
 use crate::utils::replace_variables;
 
+pub struct SourceConfig {
+    pub dir: String,
+    pub prefix: String,
+    pub file_filters: Vec<String>,
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    pub ignore_patterns: Vec<String>,
+}
+
+pub struct PathMappingConfig {
+    pub pattern: String,
+    pub replace: String,
+    pub case: String,
+}
+
+pub struct CommandRuleConfig {
+    pub patterns: Vec<String>,
+    pub delete_file: Option<Vec<Vec<String>>>,
+    pub delete_dir: Option<Vec<Vec<String>>>,
+    pub upload_file: Option<Vec<Vec<String>>>,
+    pub upload_dir: Option<Vec<Vec<String>>>,
+    pub upload_symlink: Option<Vec<Vec<String>>>,
+    pub set_mode: Option<Vec<Vec<String>>>,
+}
+
+/// 发布模式的配置，新的内容先上传到暂存前缀下，全部成功之后再执行promote命令
+pub struct PublishConfig {
+    /// 版本名称，为空时使用当前的unix时间戳
+    pub release: String,
+    /// 暂存前缀，可以使用$release变量
+    pub staging_prefix: String,
+    /// 保留的版本数量，为0时保留所有的版本
+    pub keep_releases: u32,
+    /// 记录发布历史的本地文件
+    pub history_file: String,
+    pub promote: Vec<Vec<String>>,
+    pub remove_release: Vec<Vec<String>>,
+}
+
+/// 更新清单的配置，所有的文件都上传成功之后会根据状态生成清单文件并上传
+pub struct ManifestConfig {
+    /// nested, flat或者template
+    pub format: String,
+    /// 生成的清单文件的本地路径
+    pub file: String,
+    /// template格式使用的模板
+    pub header: String,
+    pub item: String,
+    pub separator: String,
+    pub footer: String,
+    pub upload: Vec<Vec<String>>,
+}
+
+/// 状态快照的配置，每次更新状态文件时都会在状态文件旁边保存一份快照，用于回滚
+pub struct SnapshotConfig {
+    /// 保留的快照数量，为0时保留所有的快照
+    pub keep: u32,
+    /// 回滚时读取文件内容的本地归档目录，可以使用$label变量
+    pub archive_dir: String,
+}
+
 pub struct AppConfig {
-    pub source_dir: String,
+    pub target: Option<String>,
+    pub sources: Vec<SourceConfig>,
     pub state_file: String,
     pub overlay_mode: bool,
-    pub fast_comparison: bool,
+    pub operation_order: String,
+    pub comparison: String,
+    pub mtime_tolerance: u64,
+    pub comparison_stages: Option<Vec<String>>,
     pub use_local_state: bool,
     pub use_remote_state: bool,
+    pub signing_key_file: String,
+    pub signing_key_env: String,
+    pub verify_key: String,
+    pub require_signed_state: bool,
+    pub lock_state: bool,
+    pub lock_timeout: u64,
+    pub check_remote_state: bool,
     pub state_indent: u32,
+    pub state_format: String,
     pub threads: u32,
+    pub watch_debounce: u64,
     pub command_workdir: String,
     pub file_filters: Vec<String>,
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    pub ignore_patterns: Vec<String>,
+    pub on_filter_excluded: String,
+    pub symlinks: String,
+    pub prune_empty_dirs: bool,
+    pub track_mode: bool,
+    pub track_owner: bool,
+    pub path_mappings: Vec<PathMappingConfig>,
     pub variables: HashMap<String, String>,
     pub start_up: Vec<Vec<String>>,
     pub clean_up: Vec<Vec<String>>,
     pub download_state: Vec<Vec<String>>,
     pub upload_state: Vec<Vec<String>>,
+    pub acquire_lock: Vec<Vec<String>>,
+    pub release_lock: Vec<Vec<String>>,
+    pub state_etag: Vec<Vec<String>>,
     pub delete_file: Vec<Vec<String>>,
     pub delete_dir: Vec<Vec<String>>,
     pub upload_file: Vec<Vec<String>>,
     pub upload_dir: Vec<Vec<String>>,
+    pub upload_symlink: Vec<Vec<String>>,
+    pub set_mode: Vec<Vec<String>>,
+    pub rules: Vec<CommandRuleConfig>,
+    pub publish: Option<PublishConfig>,
+    pub manifest: Option<ManifestConfig>,
+    pub snapshots: Option<SnapshotConfig>,
 }
 
 impl AppConfig {
@@ -33,60 +128,294 @@ impl AppConfig {
         // 读取配置文件
         let doc = YamlLoader::load_from_str(&string)?;
         let doc = (&doc[0]).clone();
-        let source_dir = doc["source-dir"].as_str().expect("the config field 'source-dir' must be present").to_owned();
+        AppConfig::parse_from_yaml(&doc, None)
+    }
+
+    /// 读取配置文件中的多个target，每个target都继承顶层的配置项
+    /// 
+    /// names: 要读取的target名称，为空时只读取顶层配置<br/>
+    /// all_targets: 读取配置文件中定义的所有target
+    pub fn parse_targets_from_yaml_string(string: String, names: &Vec<String>, all_targets: bool) -> AppResult<Vec<AppConfig>> {
+        let doc = YamlLoader::load_from_str(&string)?;
+        let doc = doc[0].clone();
+        let targets = doc["targets"].as_hash().map_or_else(Vec::new, |t| t.iter().collect::<Vec<(&Yaml, &Yaml)>>());
+
+        if !all_targets && names.is_empty() {
+            return Ok(vec![AppConfig::parse_from_yaml(&doc, None)?]);
+        }
+
+        let names = if all_targets {
+            targets.iter().map(|(k, _v)| k.as_str().unwrap_or("").to_owned()).collect::<Vec<String>>()
+        } else {
+            names.to_owned()
+        };
+
+        if names.is_empty() {
+            return Err(Box::new(Error::new(ErrorKind::NotFound, "no targets are defined in the config file")));
+        }
+
+        // 去掉targets节点，剩下的都是需要继承的配置项
+        let mut base = doc.as_hash().map_or_else(yaml_rust::yaml::Hash::new, |h| h.clone());
+        base.remove(&Yaml::String("targets".to_owned()));
+        let base = Yaml::Hash(base);
+
+        let mut configs = Vec::<AppConfig>::new();
+        for name in &names {
+            let target = targets.iter()
+                .find(|(k, _v)| k.as_str() == Some(name))
+                .map(|(_k, v)| *v)
+                .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("the target is not defined: {}", name)))?;
+
+            let merged = AppConfig::merge_yaml(&base, target);
+            let config = AppConfig::parse_from_yaml(&merged, Some(name))?;
+
+            // 不同的target不能共用同一个状态文件
+            let state_file = replace_variables(&config.state_file, &config.variables);
+            if let Some(other) = configs.iter().find(|c| replace_variables(&c.state_file, &c.variables) == state_file) {
+                let msg = format!("the targets '{}' and '{}' share the same state-file: {}", other.target.as_ref().unwrap(), name, state_file);
+                return Err(Box::new(Error::new(ErrorKind::InvalidInput, msg)));
+            }
+
+            configs.push(config);
+        }
+
+        Ok(configs)
+    }
+
+    /// 将overlay合并到base上，两边都是map的节点会被递归合并，其它的节点直接使用overlay里的值
+    fn merge_yaml(base: &Yaml, overlay: &Yaml) -> Yaml {
+        match (base, overlay) {
+            (Yaml::Hash(base), Yaml::Hash(overlay)) => {
+                let mut merged = base.clone();
+                for (k, v) in overlay {
+                    let value = match base.get(k) {
+                        Some(b) => AppConfig::merge_yaml(b, v),
+                        None => v.clone(),
+                    };
+                    merged.insert(k.clone(), value);
+                }
+                Yaml::Hash(merged)
+            },
+            _ => overlay.clone(),
+        }
+    }
+
+    fn parse_from_yaml(doc: &Yaml, target: Option<&str>) -> AppResult<AppConfig> {
         let state_file = doc["state-file"].as_str().unwrap_or(".state.json").to_owned();
         let overlay_mode = doc["overlay-mode"].as_bool().unwrap_or(false);
+        let operation_order = doc["operation-order"].as_str().unwrap_or("delete-first").to_owned();
+        // 兼容旧版本的fast-comparison
         let fast_comparison = doc["fast-comparison"].as_bool().unwrap_or(false);
+        let comparison = doc["comparison"].as_str().unwrap_or(if fast_comparison { "fast" } else { "hash" }).to_owned();
+        let mtime_tolerance = doc["mtime-tolerance"].as_i64().unwrap_or(0);
+        let mtime_tolerance = u64::try_from(mtime_tolerance)
+            .map_err(|_e| Error::new(ErrorKind::InvalidInput, format!("mtime-tolerance must not be negative: {}", mtime_tolerance)))?;
+        let comparison_stages: Option<Vec<String>> = doc["comparison-stages"]
+            .as_vec()
+            .map(|f| f.iter().map(|v| v.as_str().unwrap_or("").to_owned()).collect());
         let use_local_state = doc["use-local-state"].as_bool().unwrap_or(false);
         let use_remote_state = doc["use-remote-state"].as_bool().unwrap_or(true);
+        let signing_key_file = doc["signing-key-file"].as_str().unwrap_or("").to_owned();
+        let signing_key_env = doc["signing-key-env"].as_str().unwrap_or("").to_owned();
+        let verify_key = doc["verify-key"].as_str().unwrap_or("").to_owned();
+        let require_signed_state = doc["require-signed-state"].as_bool().unwrap_or(false);
+        let lock_state = doc["lock-state"].as_bool().unwrap_or(false);
+        let lock_timeout = doc["lock-timeout"].as_i64().map_or_else(|| 0, |v| v as u64);
+        let check_remote_state = doc["check-remote-state"].as_bool().unwrap_or(false);
         let state_indent = doc["state-indent"].as_i64().map_or_else(|| 0, |v| v as u32);
+        let state_format = doc["state-format"].as_str().unwrap_or("").to_owned();
         let threads = doc["threads"].as_i64().map_or_else(|| 1, |v| v as u32);
+        let watch_debounce = doc["watch-debounce"].as_i64().map_or_else(|| 2000, |v| v as u64);
         let command_workdir = doc["command-workdir"].as_str().unwrap_or("").to_owned();
         let file_filters: Vec<String> = doc["file-filters"]
             .as_vec()
             .map_or_else(|| Vec::new(), |f| f.iter().map(|v| v.as_str().unwrap_or("").to_owned()).collect());
+        let include: Vec<String> = doc["include"]
+            .as_vec()
+            .map_or_else(Vec::new, |f| f.iter().map(|v| v.as_str().unwrap_or("").to_owned()).collect());
+        let exclude: Vec<String> = doc["exclude"]
+            .as_vec()
+            .map_or_else(Vec::new, |f| f.iter().map(|v| v.as_str().unwrap_or("").to_owned()).collect());
+        let ignore_patterns: Vec<String> = doc["ignore-patterns"]
+            .as_vec()
+            .map_or_else(Vec::new, |f| f.iter().map(|v| v.as_str().unwrap_or("").to_owned()).collect());
+        let on_filter_excluded = doc["on-filter-excluded"].as_str().unwrap_or("keep").to_owned();
+        let symlinks = doc["symlinks"].as_str().unwrap_or("follow").to_owned();
+        let prune_empty_dirs = doc["prune-empty-dirs"].as_bool().unwrap_or(false);
+        let track_mode = doc["track-mode"].as_bool().unwrap_or(false);
+        let track_owner = doc["track-owner"].as_bool().unwrap_or(false);
+        let path_mappings: Vec<PathMappingConfig> = doc["path-mappings"]
+            .as_vec()
+            .map_or_else(Vec::new, |f| f.iter().map(|v| PathMappingConfig {
+                pattern: v["pattern"].as_str().expect("the field 'pattern' of every path-mappings entry must be present").to_owned(),
+                replace: v["replace"].as_str().unwrap_or("$0").to_owned(),
+                case: v["case"].as_str().unwrap_or("").to_owned(),
+            }).collect());
         let variables = doc["variables"].clone();
         let command_node = &doc["commands"];
         let start_up = AppConfig::parse_as_command_line(&command_node["start-up"]);
         let clean_up = AppConfig::parse_as_command_line(&command_node["clean-up"]);
         let download_state = AppConfig::parse_as_command_line(&command_node["download-state"]);
         let upload_state = AppConfig::parse_as_command_line(&command_node["upload-state"]);
+        let acquire_lock = AppConfig::parse_as_command_line(&command_node["acquire-lock"]);
+        let release_lock = AppConfig::parse_as_command_line(&command_node["release-lock"]);
+        let state_etag = AppConfig::parse_as_command_line(&command_node["state-etag"]);
         let delete_file = AppConfig::parse_as_command_line(&command_node["delete-file"]);
         let delete_dir = AppConfig::parse_as_command_line(&command_node["delete-dir"]);
         let upload_file = AppConfig::parse_as_command_line(&command_node["upload-file"]);
         let upload_dir = AppConfig::parse_as_command_line(&command_node["making-dir"]);
+        let upload_symlink = AppConfig::parse_as_command_line(&command_node["upload-symlink"]);
+        let set_mode = AppConfig::parse_as_command_line(&command_node["set-mode"]);
+        let rules: Vec<CommandRuleConfig> = doc["rules"]
+            .as_vec()
+            .map_or_else(Vec::new, |r| r.iter().map(|rule| {
+                let command_node = &rule["commands"];
+                CommandRuleConfig {
+                    patterns: match rule["pattern"].as_vec() {
+                        Some(patterns) => patterns.iter().map(|v| v.as_str().unwrap_or("").to_owned()).collect(),
+                        None => vec![rule["pattern"].as_str().expect("the field 'pattern' of every rules entry must be present").to_owned()],
+                    },
+                    delete_file: AppConfig::parse_as_optional_command_line(&command_node["delete-file"]),
+                    delete_dir: AppConfig::parse_as_optional_command_line(&command_node["delete-dir"]),
+                    upload_file: AppConfig::parse_as_optional_command_line(&command_node["upload-file"]),
+                    upload_dir: AppConfig::parse_as_optional_command_line(&command_node["making-dir"]),
+                    upload_symlink: AppConfig::parse_as_optional_command_line(&command_node["upload-symlink"]),
+                    set_mode: AppConfig::parse_as_optional_command_line(&command_node["set-mode"]),
+                }
+            }).collect());
+        let publish = doc["publish"].as_hash().map(|_| {
+            let publish = &doc["publish"];
+            PublishConfig {
+                release: publish["release"].as_str().unwrap_or("").to_owned(),
+                staging_prefix: publish["staging-prefix"].as_str().unwrap_or("releases/$release").trim_matches('/').to_owned(),
+                keep_releases: publish["keep-releases"].as_i64().map_or_else(|| 0, |v| v as u32),
+                history_file: publish["history-file"].as_str().unwrap_or(".releases.json").to_owned(),
+                promote: AppConfig::parse_as_command_line(&publish["promote"]),
+                remove_release: AppConfig::parse_as_command_line(&publish["remove-release"]),
+            }
+        });
+        let manifest = doc["manifest"].as_hash().map(|_| {
+            let manifest = &doc["manifest"];
+            ManifestConfig {
+                format: manifest["format"].as_str().unwrap_or("nested").to_owned(),
+                file: manifest["file"].as_str().unwrap_or(".manifest.json").to_owned(),
+                header: manifest["header"].as_str().unwrap_or("").to_owned(),
+                item: manifest["item"].as_str().unwrap_or("$remote-path $hash $length").to_owned(),
+                separator: manifest["separator"].as_str().unwrap_or("\n").to_owned(),
+                footer: manifest["footer"].as_str().unwrap_or("").to_owned(),
+                upload: AppConfig::parse_as_command_line(&manifest["upload"]),
+            }
+        });
+        let snapshots = doc["snapshots"].as_hash().map(|_| {
+            let snapshots = &doc["snapshots"];
+            SnapshotConfig {
+                keep: snapshots["keep"].as_i64().map_or_else(|| 10, |v| v as u32),
+                archive_dir: snapshots["archive-dir"].as_str().unwrap_or("").to_owned(),
+            }
+        });
 
         // 全局变量
-        let variables: HashMap<String, String> = variables.as_hash().map_or_else(|| HashMap::new(), |v| {
+        let mut variables: HashMap<String, String> = variables.as_hash().map_or_else(|| HashMap::new(), |v| {
             v.iter().map(|e| (e.0.as_str().unwrap().to_owned(), e.1.as_str().unwrap().to_owned())).collect::<HashMap<String, String>>()
         });
+        if let Some(target) = target {
+            variables.insert("target".to_owned(), target.to_owned());
+        }
 
-        // 替换变量
-        let source_dir = replace_variables(&source_dir, &variables);
+        // 源目录，未指定sources时使用source-dir作为唯一的源目录
+        let sources = match doc["sources"].as_vec() {
+            Some(sources) => sources.iter().map(|s| SourceConfig {
+                dir: replace_variables(s["dir"].as_str().expect("the field 'dir' of every sources entry must be present"), &variables),
+                prefix: replace_variables(s["prefix"].as_str().unwrap_or(""), &variables).trim_matches('/').to_owned(),
+                file_filters: s["file-filters"]
+                    .as_vec()
+                    .map_or_else(|| file_filters.clone(), |f| f.iter().map(|v| v.as_str().unwrap_or("").to_owned()).collect()),
+                include: s["include"]
+                    .as_vec()
+                    .map_or_else(|| include.clone(), |f| f.iter().map(|v| v.as_str().unwrap_or("").to_owned()).collect()),
+                exclude: s["exclude"]
+                    .as_vec()
+                    .map_or_else(|| exclude.clone(), |f| f.iter().map(|v| v.as_str().unwrap_or("").to_owned()).collect()),
+                ignore_patterns: s["ignore-patterns"]
+                    .as_vec()
+                    .map_or_else(|| ignore_patterns.clone(), |f| f.iter().map(|v| v.as_str().unwrap_or("").to_owned()).collect()),
+            }).collect::<Vec<SourceConfig>>(),
+            None => {
+                let source_dir = doc["source-dir"].as_str().expect("the config field 'source-dir' or 'sources' must be present");
+                vec![SourceConfig { 
+                    dir: replace_variables(source_dir, &variables), 
+                    prefix: "".to_owned(), 
+                    file_filters: file_filters.clone(),
+                    include: include.clone(),
+                    exclude: exclude.clone(),
+                    ignore_patterns: ignore_patterns.clone(),
+                }]
+            },
+        };
 
         Ok(AppConfig {
-            source_dir,
+            target: target.map(|t| t.to_owned()),
+            sources,
             state_file,
             overlay_mode,
-            fast_comparison,
+            operation_order,
+            comparison,
+            mtime_tolerance,
+            comparison_stages,
             use_local_state,
             use_remote_state,
+            signing_key_file,
+            signing_key_env,
+            verify_key,
+            require_signed_state,
+            lock_state,
+            lock_timeout,
+            check_remote_state,
             state_indent,
+            state_format,
             threads,
+            watch_debounce,
             command_workdir,
             file_filters,
+            include,
+            exclude,
+            ignore_patterns,
+            on_filter_excluded,
+            symlinks,
+            prune_empty_dirs,
+            track_mode,
+            track_owner,
+            path_mappings,
             variables,
             start_up,
             clean_up,
             download_state,
             upload_state,
+            acquire_lock,
+            release_lock,
+            state_etag,
             delete_file,
             delete_dir,
             upload_file,
             upload_dir,
+            upload_symlink,
+            set_mode,
+            rules,
+            publish,
+            manifest,
+            snapshots,
         })
     }
 
+    /// 与parse_as_command_line相同，但是没有写这个节点时返回None(写成空值时返回空的命令)
+    fn parse_as_optional_command_line(yaml: &Yaml) -> Option<Vec<Vec<String>>> {
+        if yaml.is_badvalue() {
+            None
+        } else {
+            Some(AppConfig::parse_as_command_line(yaml))
+        }
+    }
+
     fn parse_as_command_line(yaml: &Yaml) -> Vec<Vec<String>> {
         if !yaml.is_array() {
             let line = yaml.as_str().unwrap_or("").to_owned();
@@ -109,4 +438,18 @@ impl AppConfig {
         
         array
     }
+}
+
+impl Clone for CommandRuleConfig {
+    fn clone(&self) -> Self {
+        Self { 
+            patterns: self.patterns.clone(), 
+            delete_file: self.delete_file.clone(), 
+            delete_dir: self.delete_dir.clone(), 
+            upload_file: self.upload_file.clone(), 
+            upload_dir: self.upload_dir.clone(),
+            upload_symlink: self.upload_symlink.clone(),
+            set_mode: self.set_mode.clone(),
+        }
+    }
 }
\ No newline at end of file
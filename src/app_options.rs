@@ -3,11 +3,55 @@ use clap::Arg;
 const APP_NAME: &str = env!("CARGO_PKG_NAME");
 const VERSION: &str = env!("CARGO_PKG_VERSION");
 
+pub enum AppCommand {
+    /// 执行一次完整的同步
+    Sync,
+    /// 先执行一次完整的同步，然后持续监视源目录的变动
+    Watch,
+    /// 列出发布历史里的所有版本
+    ListReleases,
+    /// 清理掉一个已经发布的版本
+    RemoveRelease(String),
+    /// 验证一个文件的签名
+    VerifySignature(String),
+    /// 回滚到一个状态快照
+    Rollback(String),
+    /// 查看和修改状态文件
+    State(StateCommand),
+}
+
+pub enum StateCommand {
+    /// 比较两个状态文件
+    Diff { old: String, new: String, format: String },
+    /// 列出状态里某个前缀下的所有文件
+    Ls(Option<String>),
+    /// 显示状态里一个文件的详细信息
+    Show(String),
+    /// 从状态里移除一个文件/目录，下次同步时会被重新上传
+    Forget { path: String, push: bool },
+    /// 把一个本地文件按照当前的内容记录到状态里，当作已经上传过
+    Add { path: String, push: bool },
+}
+
+impl StateCommand {
+    /// 只查看状态、不修改状态的子命令，这些子命令不需要加锁
+    pub fn is_read_only(&self) -> bool {
+        matches!(self, StateCommand::Diff { .. } | StateCommand::Ls(_) | StateCommand::Show(_))
+    }
+}
+
 pub struct AppOptions {
     pub config: String,
     pub debug: bool,
     pub dryrun: bool,
     pub test_filter: bool,
+    pub test_mappings: bool,
+    pub targets: Vec<String>,
+    pub all_targets: bool,
+    pub release: Option<String>,
+    pub label: Option<String>,
+    pub force_unlock: bool,
+    pub command: AppCommand,
 }
 
 impl AppOptions {
@@ -27,20 +71,182 @@ impl AppOptions {
                 .help("run but do not execute any commands actually"))
             .arg(Arg::new("test-filter")
                 .long("test-filter")
-                .help("the all the file-filters's matchings"));
-            
+                .help("show whether every file is selected or excluded and which rule decided it"))
+            .arg(Arg::new("test-mappings")
+                .long("test-mappings")
+                .help("show the remote path of every file mapped by the path-mappings"))
+            .arg(Arg::new("target")
+                .short('t')
+                .long("target")
+                .takes_value(true)
+                .multiple_occurrences(true)
+                .help("run the specified target defined in the targets section, can be repeated"))
+            .arg(Arg::new("all-targets")
+                .long("all-targets")
+                .conflicts_with("target")
+                .help("run all the targets defined in the targets section"))
+            .arg(Arg::new("release")
+                .long("release")
+                .takes_value(true)
+                .help("specify the name of the release to publish, overrides publish.release"))
+            .arg(Arg::new("label")
+                .long("label")
+                .takes_value(true)
+                .help("attach a label to the state snapshot taken by this run, e.g. v1.2.3"))
+            .arg(Arg::new("force-unlock")
+                .long("force-unlock")
+                .help("break the lock held by another run before acquiring it, only use this when the lock is stale"))
+            .subcommand(clap::Command::new("watch")
+                .about("sync once and then keep syncing on filesystem changes until interrupted"))
+            .subcommand(clap::Command::new("list-releases")
+                .about("list the releases recorded in the publish history"))
+            .subcommand(clap::Command::new("remove-release")
+                .about("run the remove-release commands for a release and drop it from the publish history")
+                .arg(Arg::new("name")
+                    .required(true)
+                    .help("the name of the release")))
+            .subcommand(clap::Command::new("verify-signature")
+                .about("verify a state or manifest file against the detached .sig file next to it")
+                .arg(Arg::new("file")
+                    .required(true)
+                    .help("the file to verify")))
+            .subcommand(clap::Command::new("rollback")
+                .about("roll the remote back to a state snapshot, re-uploading files from the archive directory")
+                .arg(Arg::new("label")
+                    .required(true)
+                    .help("the label or the timestamp of the snapshot")))
+            .subcommand(clap::Command::new("state")
+                .about("inspect the state files")
+                .subcommand_required(true)
+                .subcommand(clap::Command::new("diff")
+                    .about("compare two state files and report the added, removed and changed entries")
+                    .arg(Arg::new("old")
+                        .required(true)
+                        .help("the old state file"))
+                    .arg(Arg::new("new")
+                        .required(true)
+                        .help("the new state file"))
+                    .arg(Arg::new("format")
+                        .long("format")
+                        .takes_value(true)
+                        .possible_values(["text", "json"])
+                        .default_value("text")
+                        .help("the output format")))
+                .subcommand(clap::Command::new("ls")
+                    .about("list the entries recorded in the state")
+                    .arg(Arg::new("prefix")
+                        .help("only list the entries under this path")))
+                .subcommand(clap::Command::new("show")
+                    .about("show everything recorded in the state about a path")
+                    .arg(Arg::new("path")
+                        .required(true)
+                        .help("the path in the state")))
+                .subcommand(clap::Command::new("forget")
+                    .about("remove a path from the state so that it gets uploaded again on the next sync")
+                    .arg(Arg::new("path")
+                        .required(true)
+                        .help("the path in the state"))
+                    .arg(Arg::new("push")
+                        .long("push")
+                        .help("upload the modified state with the upload-state commands")))
+                .subcommand(clap::Command::new("add")
+                    .about("record a local file in the state as if it had been uploaded")
+                    .arg(Arg::new("path")
+                        .required(true)
+                        .help("the path in the state"))
+                    .arg(Arg::new("push")
+                        .long("push")
+                        .help("upload the modified state with the upload-state commands"))));
+
         let matches = command.get_matches();
 
         let arg_config = matches.value_of("config").unwrap_or_else(|| "config.yml").to_owned();
         let arg_debug = matches.is_present("debug");
         let arg_dryrun = matches.is_present("dry-run");
         let arg_test_filter = matches.is_present("test-filter");
+        let arg_test_mappings = matches.is_present("test-mappings");
+        let arg_targets = matches.values_of("target").map_or_else(Vec::new, |v| v.map(|t| t.to_owned()).collect());
+        let arg_all_targets = matches.is_present("all-targets");
+        let arg_release = matches.value_of("release").map(|r| r.to_owned());
+        let arg_label = matches.value_of("label").map(|l| l.to_owned());
+        let arg_force_unlock = matches.is_present("force-unlock");
+        let arg_command = match matches.subcommand() {
+            Some(("watch", _)) => AppCommand::Watch,
+            Some(("list-releases", _)) => AppCommand::ListReleases,
+            Some(("remove-release", m)) => AppCommand::RemoveRelease(m.value_of("name").unwrap().to_owned()),
+            Some(("verify-signature", m)) => AppCommand::VerifySignature(m.value_of("file").unwrap().to_owned()),
+            Some(("rollback", m)) => AppCommand::Rollback(m.value_of("label").unwrap().to_owned()),
+            Some(("state", m)) => AppCommand::State(match m.subcommand() {
+                Some(("diff", m)) => StateCommand::Diff {
+                    old: m.value_of("old").unwrap().to_owned(),
+                    new: m.value_of("new").unwrap().to_owned(),
+                    format: m.value_of("format").unwrap().to_owned(),
+                },
+                Some(("ls", m)) => StateCommand::Ls(m.value_of("prefix").map(|p| p.to_owned())),
+                Some(("show", m)) => StateCommand::Show(m.value_of("path").unwrap().to_owned()),
+                Some(("forget", m)) => StateCommand::Forget { path: m.value_of("path").unwrap().to_owned(), push: m.is_present("push") },
+                Some(("add", m)) => StateCommand::Add { path: m.value_of("path").unwrap().to_owned(), push: m.is_present("push") },
+                _ => unreachable!(),
+            }),
+            _ => AppCommand::Sync,
+        };
 
         AppOptions {
             config: arg_config,
             debug: arg_debug,
             dryrun: arg_dryrun,
             test_filter: arg_test_filter,
+            test_mappings: arg_test_mappings,
+            targets: arg_targets,
+            all_targets: arg_all_targets,
+            release: arg_release,
+            label: arg_label,
+            force_unlock: arg_force_unlock,
+            command: arg_command,
+        }
+    }
+}
+
+impl Clone for AppCommand {
+    fn clone(&self) -> Self {
+        match self {
+            AppCommand::Sync => AppCommand::Sync,
+            AppCommand::Watch => AppCommand::Watch,
+            AppCommand::ListReleases => AppCommand::ListReleases,
+            AppCommand::RemoveRelease(name) => AppCommand::RemoveRelease(name.clone()),
+            AppCommand::VerifySignature(file) => AppCommand::VerifySignature(file.clone()),
+            AppCommand::Rollback(label) => AppCommand::Rollback(label.clone()),
+            AppCommand::State(command) => AppCommand::State(command.clone()),
+        }
+    }
+}
+
+impl Clone for StateCommand {
+    fn clone(&self) -> Self {
+        match self {
+            StateCommand::Diff { old, new, format } => StateCommand::Diff { old: old.clone(), new: new.clone(), format: format.clone() },
+            StateCommand::Ls(prefix) => StateCommand::Ls(prefix.clone()),
+            StateCommand::Show(path) => StateCommand::Show(path.clone()),
+            StateCommand::Forget { path, push } => StateCommand::Forget { path: path.clone(), push: *push },
+            StateCommand::Add { path, push } => StateCommand::Add { path: path.clone(), push: *push },
+        }
+    }
+}
+
+impl Clone for AppOptions {
+    fn clone(&self) -> Self {
+        Self { 
+            config: self.config.clone(), 
+            debug: self.debug, 
+            dryrun: self.dryrun, 
+            test_filter: self.test_filter, 
+            test_mappings: self.test_mappings, 
+            targets: self.targets.clone(), 
+            all_targets: self.all_targets, 
+            release: self.release.clone(),
+            label: self.label.clone(),
+            force_unlock: self.force_unlock,
+            command: self.command.clone(),
         }
     }
 }
\ No newline at end of file
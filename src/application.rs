@@ -1,53 +1,143 @@
 use std::cell::Cell;
 use std::env;
+use std::fs;
 use std::io::Error;
 use std::io::ErrorKind;
+use std::panic;
+use std::panic::AssertUnwindSafe;
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
 
 use crate::AppResult;
 use crate::app_config::AppConfig;
+use crate::app_config::SourceConfig;
+use crate::app_options::AppCommand;
 use crate::app_options::AppOptions;
+use crate::app_options::StateCommand;
 use crate::blocking_thread_pool::BlockingThreadPool;
+use crate::command_rule::CommandRule;
+use crate::command_rule::Operation;
+use crate::comparison::ComparisonMode;
+use crate::comparison::StagedComparison;
 use crate::file::File;
+use crate::differences::Differences;
+use crate::differences::OperationOrder;
+use crate::file_comparer::ExcludedPolicy;
 use crate::file_comparer::FileComparer;
+use crate::file_metadata::FileMetadata;
+use crate::file_metadata::MetadataTracking;
 use crate::file_state::State;
+use crate::file_watcher::FileWatcher;
 use crate::hash_cache::HashCache;
-use crate::rule_filter::RuleFilter;
+use crate::manifest::Manifest;
+use crate::manifest::ManifestFormat;
+use crate::path_mapping::PathMapper;
+use crate::release::Release;
+use crate::release::ReleaseHistory;
+use crate::simple_file::DirData;
 use crate::simple_file::FileData;
+use crate::simple_file::SimpleFile;
+use crate::signing::Signing;
+use crate::snapshot::SnapshotStore;
+use crate::state_diff::DiffFormat;
+use crate::state_diff::StateDiff;
+use crate::state_format::StateFormat;
+use crate::state_lock::StateLock;
+use crate::state_lock::StateLockGuard;
+use crate::source::Source;
+use crate::symlink_policy::LocalKind;
+use crate::symlink_policy::SymlinkPolicy;
 use crate::subprocess_task::SubprocessResult;
 use crate::subprocess_task::SubprocessTask;
+use crate::utils::get_dirname;
 use crate::variable_replace::VariableReplace;
 
 pub struct App {
     options: AppOptions,
     config: AppConfig,
     variables: VariableReplace,
-    hash_cache: Arc<HashCache>,
-    file_filter: RuleFilter,
-    sourcedir: File,
+    sources: Arc<Vec<Source>>,
+    path_mapper: PathMapper,
+    rules: Vec<CommandRule>,
+    excluded_policy: ExcludedPolicy,
+    symlinks: SymlinkPolicy,
+    tracking: MetadataTracking,
+    comparison_mode: ComparisonMode,
+    comparison: Arc<StagedComparison>,
+    operation_order: OperationOrder,
+    /// 发布模式下正在发布的版本
+    publishing: Option<Release>,
+    /// 是否已经执行过promote命令，之后出现的错误不会影响已经发布的版本
+    promoted: AtomicBool,
+    signing: Signing,
+    state_format: StateFormat,
+    /// 本地状态文件的锁，没有开启lock-state时为None
+    state_lock: Option<StateLock>,
+    /// 下载远端状态时记录的版本，开启了check-remote-state时才会记录
+    remote_state_baseline: Mutex<Option<String>>,
+    /// 下载到的远端状态文件的内容，发生冲突时作为合并的基准保存下来
+    remote_state_base: Mutex<Option<Vec<u8>>>,
+    /// 是否合并了上一次运行因为冲突而保留下来的状态
+    conflict_merged: AtomicBool,
     workdir: File,
 }
 
 impl App {
-    pub fn new() -> AppResult<App> {
-        let options = AppOptions::parse_from_command_line();
-
+    /// 读取配置文件，每个被选中的target都会得到一份单独的配置
+    pub fn load_configs(options: &AppOptions) -> AppResult<Vec<AppConfig>> {
         // 检查参数
         let config_file = File::new(&options.config);
         if !config_file.is_file() {
             return Err(Box::new(Error::new(ErrorKind::NotFound, String::from(format!("the config file is not a file: {}", options.config)))))
         }
 
-        let config = AppConfig::parse_from_yaml_string(config_file.read()?)?;
-        
+        let configs = AppConfig::parse_targets_from_yaml_string(config_file.read()?, &options.targets, options.all_targets)?;
+
+        if let AppCommand::Watch = options.command {
+            if configs.len() > 1 {
+                return Err(Box::new(Error::new(ErrorKind::InvalidInput, "the watch command can only run one target at a time")))
+            }
+        }
+
+        Ok(configs)
+    }
+
+    pub fn new(options: AppOptions, config: AppConfig) -> AppResult<App> {
         // 检查参数
-        let source_dir = &config.source_dir;
-        let source_dir = if source_dir.ends_with("/") { &source_dir[0..source_dir.len() - 1] } else { &source_dir[..] }.to_owned();
-        let sourcedir = File::new(&source_dir);
-        if !sourcedir.is_dir() {
-            return Err(Box::new(Error::new(ErrorKind::NotFound, String::from(format!("the source-directory is not a dir: {}", config.source_dir)))))
+        let mut sources = Vec::<Source>::new();
+        for source in &config.sources {
+            sources.push(Source::new(source)?);
+        }
+        Source::check_prefixes(&sources)?;
+        let path_mapper = PathMapper::new(&config.path_mappings)?;
+        let mut rules = Vec::<CommandRule>::new();
+        for rule in &config.rules {
+            rules.push(CommandRule::new(rule)?);
+        }
+        let excluded_policy = ExcludedPolicy::parse(&config.on_filter_excluded)?;
+        let symlinks = SymlinkPolicy::parse(&config.symlinks)?;
+        let tracking = MetadataTracking::new(config.track_mode, config.track_owner);
+        let comparison_mode = ComparisonMode::parse(&config.comparison)?;
+        let operation_order = OperationOrder::parse(&config.operation_order)?;
+        if let Some(manifest) = &config.manifest {
+            ManifestFormat::parse(&manifest.format)?;
         }
+        let signing = Signing::new(&config.signing_key_file, &config.signing_key_env, &config.verify_key)?;
+        if config.require_signed_state && !signing.can_verify() {
+            let msg = "require-signed-state is enabled but neither verify-key nor a signing key (signing-key-file/signing-key-env) is configured";
+            return Err(Box::new(Error::new(ErrorKind::InvalidInput, msg)));
+        }
+        let state_format = StateFormat::for_file(&config.state_format, &config.state_file)?;
+        let stages = config.comparison_stages.clone().unwrap_or_else(|| comparison_mode.default_stages());
+        let comparison = StagedComparison::new(&stages, config.mtime_tolerance.saturating_mul(1_000_000))?;
+        let sourcedir = sources.first()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "at least one source-directory is required"))?
+            .dir.clone();
 
         let workdir = &config.command_workdir;
         let workdir = if workdir.len() > 0 { File::new(&workdir) } else { File::from(env::current_dir().expect("failed to get Current Work Directory."))};
@@ -55,9 +145,6 @@ impl App {
             return Err(Box::new(Error::new(ErrorKind::NotFound, String::from(format!("the workdir is not a dir: {}", workdir.path())))))
         }
 
-        let hash_cache = Arc::new(HashCache::new(&sourcedir));
-        let file_filter = RuleFilter::new(&config.file_filters)?;
-
         let mut variables = VariableReplace::new();
         variables.variables.extend(config.variables.to_owned());
 
@@ -65,30 +152,69 @@ impl App {
         variables.add("workdir", &workdir.path());
         variables.add("source_", &sourcedir.path().replace("\\", "/"));
         variables.add("workdir_", &workdir.path().replace("\\", "/"));
+
+        // 发布模式下的版本名称和暂存前缀
+        let publishing = match &config.publish {
+            Some(publish) => {
+                let time = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+                let name = match &options.release {
+                    Some(release) => release.to_owned(),
+                    None if !publish.release.is_empty() => variables.apply(&publish.release),
+                    None => time.to_string(),
+                };
+                variables.add("release", &name);
+                let staging = variables.apply(&publish.staging_prefix);
+                variables.add("staging", &staging);
+
+                Some(Release { name, staging, time })
+            },
+            None => None,
+        };
+
+        // 使用本地状态时，在状态文件旁边加锁
+        let state_lock = if config.lock_state && config.use_local_state {
+            Some(StateLock::new(File::new(&(variables.apply(&config.state_file) + ".lock"))))
+        } else {
+            None
+        };
         
         Ok(App {
             options,
             config,
             variables,
-            hash_cache,
-            file_filter,
-            sourcedir,
+            sources: Arc::new(sources),
+            path_mapper,
+            rules,
+            excluded_policy,
+            symlinks,
+            tracking,
+            comparison_mode,
+            comparison: Arc::new(comparison),
+            operation_order,
+            publishing,
+            promoted: AtomicBool::new(false),
+            signing,
+            state_format,
+            state_lock,
+            remote_state_baseline: Mutex::new(None),
+            remote_state_base: Mutex::new(None),
+            conflict_merged: AtomicBool::new(false),
             workdir,
         })
     }
 
+    /// 使用多个线程执行命令，tasks里的每一项都是一个文件要执行的命令和所使用的变量
     fn execute_multiple_thread(
         &self, 
-        commands: &Vec<Vec<String>>, 
+        tasks: &[(Vec<Vec<String>>, VariableReplace)], 
         parallel: usize, 
-        varses: &Vec<VariableReplace>,
         before_execute: Box<dyn Fn(&VariableReplace) + Send + Sync>,
-        after_execute: Box<dyn Fn(&VariableReplace) + Send + Sync>
+        after_execute: Box<dyn Fn(&VariableReplace) -> std::io::Result<()> + Send + Sync>
     ) -> AppResult<()> {
         let mut pool = BlockingThreadPool::new(parallel);
         let after_execute = Arc::new(after_execute);
 
-        for vars in varses {
+        for (commands, vars) in tasks {
             let vars = vars.clone();
             let workdir = self.workdir.clone();
             let debug = self.options.debug;
@@ -116,23 +242,27 @@ impl App {
                     last_result = Some(r);
                 }
 
-                after_execute(&vars);
-
-                Ok(())
+                after_execute(&vars).map_err(|e| Box::new(e) as Box<dyn std::error::Error + Send>)
             });
+        }
 
-            let r = pool.close_and_wait();
+        let r = pool.close_and_wait();
 
-            if r.is_err() {
-                let err = r.err().unwrap();
-                return Err(err);
-            }
+        if r.is_err() {
+            let err = r.err().unwrap();
+            return Err(err);
         }
 
         Ok(())
     }
 
     fn execute_single_thread(&self, commands: &Vec<Vec<String>>, vars: &VariableReplace) -> AppResult<()> {
+        self.execute_for_result(commands, vars)?;
+        Ok(())
+    }
+
+    /// 依次执行多条命令，返回最后一条命令的执行结果
+    fn execute_for_result(&self, commands: &Vec<Vec<String>>, vars: &VariableReplace) -> AppResult<Option<SubprocessResult>> {
         let mut last_result: Option<SubprocessResult> = None;
         for step in commands {
             let mut task = SubprocessTask::from_command_line(
@@ -146,7 +276,7 @@ impl App {
             last_result = Some(task.execute(false)?);
         }
 
-        Ok(())
+        Ok(last_result)
     }
 
     fn get_state_file(&self) -> File {
@@ -162,55 +292,164 @@ impl App {
                 println!("从本地加载状态文件")
             } else if use_remote_state {
                 println!("从远端更新状态文件");
+
+                // 删掉上一次留下的签名，避免远端没有签名时误用旧的签名
+                let signature_file = Signing::signature_file(state_file);
+                if self.config.require_signed_state && signature_file.exists() {
+                    signature_file.rm()?;
+                }
+
                 if !self.config.download_state.is_empty() {
                     self.execute_single_thread(&self.config.download_state, &self.variables)?;
                 }
+
+                // 拒绝没有签名或者签名不正确的远端状态文件
+                if self.config.require_signed_state && state_file.exists() {
+                    self.signing.verify_file(state_file)?;
+                }
+
+                // 记录下载到的远端状态的版本，上传之前用来检查远端状态有没有被别人修改过
+                if self.config.check_remote_state {
+                    let version = self.remote_state_version(state_file)?;
+                    *self.remote_state_baseline.lock().unwrap() = Some(version);
+                    *self.remote_state_base.lock().unwrap() = if state_file.is_file() { Some(fs::read(state_file.get_raw())?) } else { None };
+                }
             }
 
             if !state_file.exists() {
                 println!("未找到任何状态文件!使用默认的空状态!");
-                json::JsonValue::new_array()
+                State::from_json_array(&json::JsonValue::new_array())
             } else {
-                json::parse(&state_file.read().unwrap()[..])
-                .expect(&format!("状态文件无法解析为Json格式: {}", state_file.path())[..])
+                StateFormat::read(state_file)
+                    .unwrap_or_else(|e| panic!("状态文件无法解析: {}: {}", state_file.path(), e))
             }
         } else {
             println!("不加载任何状态文件!使用默认的空状态!");
-            json::JsonValue::new_array()
+            State::from_json_array(&json::JsonValue::new_array())
         };
         
-        Ok(State::from_json_array(&state))
+        self.merge_conflicting_state(state_file, state)
+    }
+
+    /// 上一次运行因为冲突而没有上传的状态文件，以及它所基于的远端状态
+    fn conflict_files(state_file: &File) -> (File, File) {
+        (File::new(&(state_file.path() + ".conflict")), File::new(&(state_file.path() + ".conflict-base")))
+    }
+
+    /// 把上一次运行因为冲突而没有上传的修改合并到现在的状态上，双方都修改了的文件以上一次运行的为准
+    fn merge_conflicting_state(&self, state_file: &File, mut state: State) -> AppResult<State> {
+        let (conflict, base) = App::conflict_files(state_file);
+        if !conflict.exists() {
+            return Ok(state);
+        }
+
+        println!("合并上一次运行因为冲突而没有上传的状态: {}", conflict.path());
+        let ours = StateFormat::read(&conflict)?;
+        let base = if base.exists() { StateFormat::read(&base)? } else { State::from_json_array(&json::JsonValue::new_array()) };
+        state.rebase(&base, &ours)?;
+        self.conflict_merged.store(true, Ordering::SeqCst);
+
+        Ok(state)
     }
 
-    pub fn save_state_file(&self, comparer: &FileComparer, state_file: &File, state: &State) -> AppResult<()> {
+    /// 远端状态的版本：配置了state-etag命令时为命令的输出，否则为本地状态文件的hash(不存在时为空)
+    fn remote_state_version(&self, state_file: &File) -> AppResult<String> {
+        if !self.config.state_etag.is_empty() {
+            let result = self.execute_for_result(&self.config.state_etag, &self.variables)?;
+            return Ok(result.map_or_else(String::new, |r| r.stdout.trim().to_owned()));
+        }
+
+        Ok(if state_file.is_file() { state_file.sha1()? } else { "".to_owned() })
+    }
+
+    /// 上传状态文件之前检查远端状态有没有在同步期间被别人修改过，没有配置state-etag命令时会重新下载一遍状态文件
+    fn check_remote_state(&self, state_file: &File) -> AppResult<()> {
+        let expected = match self.remote_state_baseline.lock().unwrap().clone() {
+            Some(expected) => expected,
+            None => return Ok(()),
+        };
+
+        println!("检查远端状态文件...");
+        if self.config.state_etag.is_empty() && !self.config.download_state.is_empty() {
+            self.execute_single_thread(&self.config.download_state, &self.variables)?;
+        }
+
+        let current = self.remote_state_version(state_file)?;
+        if current != expected {
+            return Err(Box::new(Error::new(ErrorKind::AlreadyExists, format!(
+                "the remote state was changed by someone else during the sync (expected '{}', found '{}')", expected, current
+            ))));
+        }
+
+        Ok(())
+    }
+
+    /// 把状态写到本地的状态文件里(先写临时文件再替换)，同时保存快照和签名，返回签名文件
+    fn write_state_file(&self, state_file: &File, state: &State) -> AppResult<Option<File>> {
+        let file_contents = self.state_format.encode(state, self.config.state_indent as u16)?;
+
+        state_file.parent()?.unwrap().mkdirs()?;
+        state_file.write_atomically(&file_contents)?;
+        self.take_snapshot(state_file, &file_contents)?;
+
+        self.signing.sign_file(state_file)
+    }
+
+    pub fn save_state_file(&self, has_differences: bool, state_file: &File, state: &State) -> AppResult<()> {
         let update_local_state = self.config.use_local_state;
         let update_remote_state = self.config.use_remote_state;
 
-        if comparer.differences.has_differences() && (update_local_state || update_remote_state) {
+        // 合并过的状态即使这次没有任何变动也需要上传
+        let has_differences = has_differences || self.conflict_merged.load(Ordering::SeqCst);
+
+        if has_differences && (update_local_state || update_remote_state) {
             if update_local_state {
                 println!("更新本地状态文件...");
             }
             
-            if state_file.exists() {
-                state_file.rm()?;
+            // 远端状态在同步期间被别人修改过时不能覆盖掉，把这次的状态和它所基于的远端状态另外保存下来，下一次运行时再合并
+            if update_remote_state {
+                if let Err(e) = self.check_remote_state(state_file) {
+                    let (conflict, base) = App::conflict_files(state_file);
+                    conflict.write_atomically(self.state_format.encode(state, self.config.state_indent as u16)?)?;
+                    match self.remote_state_base.lock().unwrap().as_ref() {
+                        Some(contents) => base.write_atomically(contents)?,
+                        None => if base.exists() { base.rm()? },
+                    }
+                    println!("远端状态文件已经被修改过，不上传状态文件，这次的状态保存在: {}", conflict.path());
+                    return Err(e);
+                }
             }
-            
-            let file_contents = state.to_json_array();
-            let file_contents = if self.config.state_indent > 0 { 
-                file_contents.pretty(self.config.state_indent as u16)
-            } else { 
-                file_contents.dump() 
-            };
 
-            state_file.parent()?.unwrap().mkdirs()?;
-            state_file.write(&file_contents)?;
+            let signature = self.write_state_file(state_file, state)?;
 
             // 更新远端状态文件
             if update_remote_state {
                 println!("更新远端状态文件...");
 
                 if !self.config.upload_state.is_empty() {
-                    self.execute_single_thread(&self.config.upload_state, &self.variables)?;
+                    let mut vars = self.variables.to_owned();
+                    if let Some(signature) = &signature {
+                        vars.add("signature", &signature.path());
+                    }
+                    self.execute_single_thread(&self.config.upload_state, &vars)?;
+                }
+
+                // 现在远端是自己刚刚上传的状态了
+                if self.remote_state_baseline.lock().unwrap().is_some() {
+                    let version = self.remote_state_version(state_file)?;
+                    *self.remote_state_baseline.lock().unwrap() = Some(version);
+                    *self.remote_state_base.lock().unwrap() = Some(fs::read(state_file.get_raw())?);
+                }
+
+                // 冲突时保留下来的状态已经合并并上传了
+                if self.conflict_merged.swap(false, Ordering::SeqCst) {
+                    let (conflict, base) = App::conflict_files(state_file);
+                    for file in [conflict, base] {
+                        if file.exists() {
+                            file.rm()?;
+                        }
+                    }
                 }
             }
 
@@ -219,228 +458,1123 @@ impl App {
                 if state_file.exists() {
                     state_file.rm()?;
                 }
+                if let Some(signature) = &signature {
+                    signature.rm()?;
+                }
             }
         }
 
         Ok(())
     }
 
-    pub fn compare_files(&self, state: &State) -> AppResult<FileComparer> {
-        let compare_func = |remote: &FileData, local: &File, path: &str, fast_comparison: bool, hash_cache: &HashCache, debug_mode: bool| -> bool {
-            (fast_comparison && remote.modified == local.modified().map_or_else(|_e| 0, |v| v)) || 
-            remote.sha1 == hash_cache.get_hash(path, debug_mode)
+    fn create_comparer<'a>(&'a self, source: &'a Source) -> FileComparer<'a> {
+        let comparison = self.comparison.clone();
+        let compare_func = move |remote: &FileData, local: &File, path: &str, hash_cache: &HashCache, debug_mode: bool| -> bool {
+            comparison.compare(remote, local, path, hash_cache, debug_mode)
         };
         
+        let mut comparer = FileComparer::new(source, Box::new(compare_func), self.comparison_mode.refreshes_modified(), &self.excluded_policy, &self.symlinks, &self.tracking, self.options.debug);
+        comparer.prune_empty_dirs = self.config.prune_empty_dirs;
+        comparer
+    }
+
+    pub fn compare_files(&self, state: &State) -> AppResult<Differences> {
         // 计算差异
-        let mut comparer = FileComparer::new(&self.sourcedir, Box::new(compare_func), &self.hash_cache, self.config.fast_comparison, &self.file_filter, self.options.debug);
         println!("正在计算文件差异...");
-        comparer.compare(&self.sourcedir, &state)?;
+        let mut differences = self.find_orphaned_files(state);
+        for source in self.sources.iter() {
+            let mut comparer = self.create_comparer(source);
+            comparer.compare(state)?;
+            differences.merge(comparer.differences);
+        }
+
+        if self.options.debug {
+            self.comparison.report();
+        }
+
+        Ok(differences)
+    }
+
+    /// 找出状态里不属于任何一个源目录的文件和目录(比如被移除的源目录)，它们都需要被删除
+    fn find_orphaned_files(&self, state: &State) -> Differences {
+        fn add_all(file: &SimpleFile, path: &str, differences: &mut Differences) {
+            if let Some(dir) = file.as_dir() {
+                for f in &dir.files {
+                    add_all(f, &(path.to_owned() + "/" + &f.name), differences);
+                }
+                differences.old_folders.push(path.to_owned());
+            } else {
+                differences.old_files.push(path.to_owned());
+            }
+        }
+
+        fn walk(dir: &DirData, path: &str, prefixes: &[&str], differences: &mut Differences) {
+            for f in &dir.files {
+                let current = if path.is_empty() { f.name.to_owned() } else { path.to_owned() + "/" + &f.name };
+
+                if prefixes.contains(&&current[..]) {
+                    continue;
+                }
+
+                // 前缀的上级目录
+                if f.is_dir() && prefixes.iter().any(|p| p.starts_with(&(current.to_owned() + "/"))) {
+                    walk(f.as_dir().unwrap(), &current, prefixes, differences);
+                    continue;
+                }
+
+                add_all(f, &current, differences);
+            }
+        }
+
+        let mut differences = Differences::new();
+        let prefixes = self.sources.iter().map(|s| &s.prefix[..]).collect::<Vec<&str>>();
+
+        // 没有前缀的源目录对应的是整个状态
+        if !prefixes.contains(&"") {
+            walk(&state.files, "", &prefixes, &mut differences);
+        }
+
+        differences
+    }
+
+    /// 只计算发生了变动的路径所在的子目录的差异
+    pub fn compare_changed_files(&self, state: &State, changes: &[File]) -> AppResult<Differences> {
+        let mut differences = Differences::new();
+
+        for source in self.sources.iter() {
+            // 找出每个变动路径最近的一个在本地和状态中都是目录的上级目录
+            let mut subtrees: Vec<String> = Vec::new();
+            for path in changes.iter().filter_map(|c| source.relativize(c)) {
+                let mut current = Some(&path[..]);
+                while let Some(dir) = current {
+                    if dir.is_empty() || dir == "." {
+                        current = None;
+                        break;
+                    }
+
+                    let in_state = state.files.get_file(&source.to_state_path(dir)).map_or_else(|| false, |f| f.is_dir());
+                    if in_state && source.dir.append(dir)?.is_dir() {
+                        break;
+                    }
+
+                    current = get_dirname(dir);
+                }
 
-        Ok(comparer)
+                let subtree = current.unwrap_or("").to_owned();
+                if !subtrees.contains(&subtree) {
+                    subtrees.push(subtree);
+                }
+            }
+
+            // 去掉已经被上级目录包含的子目录
+            let covered = |dir: &str, by: &str| by.is_empty() || dir.starts_with(&(by.to_owned() + "/"));
+            let subtrees = subtrees.iter()
+                .filter(|dir| !subtrees.iter().any(|other| other != *dir && covered(dir, other)))
+                .collect::<Vec<&String>>();
+
+            let mut comparer = self.create_comparer(source);
+            for subtree in subtrees {
+                if self.options.debug {
+                    println!("正在计算子目录差异: {}", source.dir.append(subtree)?.path());
+                }
+                comparer.compare_subtree(subtree, state)?;
+            }
+            differences.merge(comparer.differences);
+        }
+
+        if self.options.debug {
+            self.comparison.report();
+        }
+
+        Ok(differences)
+    }
+
+    /// 获取某个文件/目录的操作所要执行的命令，rules里第一条匹配的规则优先，否则使用默认的命令
+    fn commands_for(&self, path: &str, operation: Operation) -> &Vec<Vec<String>> {
+        // 规则里可能有文件属性条件，需要用本地文件来判断
+        let local_file = Source::locate(&self.sources, path).and_then(|(source, local_path)| source.dir.append(&local_path).ok());
+        if let Some(commands) = CommandRule::find_commands(&self.rules, path, local_file.as_ref(), &operation) {
+            return commands;
+        }
+
+        match operation {
+            Operation::DeleteFile => &self.config.delete_file,
+            Operation::DeleteDir => &self.config.delete_dir,
+            Operation::UploadFile => &self.config.upload_file,
+            Operation::MakeDir => &self.config.upload_dir,
+            Operation::UploadSymlink => &self.config.upload_symlink,
+            Operation::SetMode => &self.config.set_mode,
+        }
     }
 
-    pub fn execute_operations(&self, comparer: &FileComparer, state: Arc<Mutex<Cell<State>>>) -> AppResult<()> {
-        let diff = &comparer.differences;
+    /// 生成操作单个文件/目录时所使用的变量
+    /// 
+    /// path: 文件/目录在状态里的路径
+    fn file_variables(&self, path: &str) -> VariableReplace {
+        let mut vars = self.variables.to_owned();
+        vars.add("path", path);
+        vars.add("path_", &path.replace("/", "\\"));
+
+        // 远端路径
+        let remote_path = self.path_mapper.map(path);
+        vars.add("remote-path", &remote_path);
+        vars.add("remote-path_", &remote_path.replace("/", "\\"));
+
+        // 文件所在的源目录
+        if let Some((source, local_path)) = Source::locate(&self.sources, path) {
+            let local_file = source.dir.append(&local_path).unwrap();
+            vars.add("local-path", &local_file.path());
+            vars.add("local-path_", &local_file.path().replace("/", "\\"));
+            vars.add("source", &source.dir.path());
+            vars.add("source_", &source.dir.path().replace("\\", "/"));
+
+            // 文件的权限和所有者
+            if self.tracking.is_enabled() {
+                let metadata = FileMetadata::read(&local_file);
+                if let Some(mode) = metadata.mode {
+                    vars.add("mode", &format!("{:o}", mode));
+                }
+                if let Some(uid) = metadata.uid {
+                    vars.add("uid", &uid.to_string());
+                }
+                if let Some(gid) = metadata.gid {
+                    vars.add("gid", &gid.to_string());
+                }
+            }
+        }
 
+        vars
+    }
+
+    /// 生成上传文件/目录时所使用的变量，发布模式下远端路径会指向暂存前缀下
+    fn upload_variables(&self, path: &str) -> VariableReplace {
+        let mut vars = self.file_variables(path);
+
+        if let Some(release) = &self.publishing {
+            let remote_path = self.path_mapper.map(path);
+            let remote_path = if release.staging.is_empty() { remote_path } else { release.staging.to_owned() + "/" + &remote_path };
+            vars.add("remote-path", &remote_path);
+            vars.add("remote-path_", &remote_path.replace("/", "\\"));
+        }
+
+        vars
+    }
+
+    pub fn execute_operations(&self, diff: &Differences, state: Arc<Mutex<Cell<State>>>) -> AppResult<()> {
         println!(
             "旧文件: {}, 旧目录: {}, 新文件: {}, 新目录: {}", 
             diff.old_files.len(), diff.old_folders.len(),
             diff.new_files.len(), diff.new_folders.len(),
         );
 
+        if !diff.new_symlinks.is_empty() {
+            println!("符号链接: {}", diff.new_symlinks.len());
+        }
+
+        if !diff.changed_metadata.is_empty() {
+            println!("权限变化: {}", diff.changed_metadata.len());
+        }
+
+        if !diff.excluded.is_empty() {
+            println!("被过滤器排除的已记录文件: {} (on-filter-excluded: {})", diff.excluded.len(), self.config.on_filter_excluded);
+        }
+
         // 执行用户初始化指令
-        if comparer.differences.has_differences() && !self.config.start_up.is_empty() {
+        if diff.has_differences() && !self.config.start_up.is_empty() {
             self.execute_single_thread(&self.config.start_up, &self.variables)?;
         }
         
-        // 删除文件
-        {
-            let filtered_old_files = diff.old_files
-                .iter()
-                .filter_map(|e| if self.config.overlay_mode && diff.new_files.contains(e) { None } else { Some(&e[..]) })
-                .collect::<Vec<&str>>();
-            let total = filtered_old_files.len();
-            let done = Arc::new(Mutex::new(0));
-
-            if !self.config.delete_file.is_empty() {
-                let varses = filtered_old_files.iter().map(|f| {
-                    let mut vars = self.variables.to_owned();
-                    vars.add("path", f);
-                    vars.add("path_", &f.replace("/", "\\"));
-                    vars
-                }).collect::<Vec<VariableReplace>>();
-
-                let state = state.clone();
-
-                self.execute_multiple_thread(
-                    &self.config.delete_file, 
-                    self.config.threads as usize, 
-                    &varses, 
-                    Box::new(move |vars| {
-                        let mut done = done.lock().unwrap();
-                        *done += 1;
-                        println!("删除文件({}/{}): {}", done, total, vars.variables.get("path").unwrap());
-                    }),
-                    Box::new(move |vars| {
-                        let path = vars.variables.get("path").unwrap();
-                        state.clone().lock().unwrap().get_mut().remove_file_or_dir(&path);
-                    })
-                )?;
-            } else {
-                for f in &filtered_old_files {
-                    let mut done = done.lock().unwrap();
-                    *done += 1;
-                    state.lock().unwrap().get_mut().remove_file_or_dir(&f);
-                    println!("删除文件({}/{}): {}", done, total, f);
+        let old_files = diff.old_files.iter().collect::<Vec<&String>>();
+        let old_folders = diff.old_folders_deepest_first();
+
+        if let Some(release) = &self.publishing {
+            // 发布模式：新的内容先上传到暂存前缀下，执行promote之后才删除旧的文件，被新内容替换掉的文件/目录不需要再删除
+            let conflicts = diff.conflict_index();
+            let old_files = old_files.into_iter().filter(|f| !conflicts.conflicts_with_new(f, true)).collect::<Vec<&String>>();
+            let old_folders = old_folders.into_iter().filter(|f| !conflicts.conflicts_with_new(f, true)).collect::<Vec<&String>>();
+
+            self.make_dirs(&diff.upload_folders_shallowest_first().iter().collect::<Vec<&String>>(), &state)?;
+            self.upload_files(diff, &state)?;
+            self.upload_symlinks(diff, &state)?;
+            if diff.has_differences() {
+                // 清单里不能包含即将被删除的文件
+                let mut projected = state.lock().unwrap().get_mut().clone();
+                for f in old_files.iter().copied().chain(diff.forgotten.iter()).chain(old_folders.iter().copied()) {
+                    if projected.files.contains_file(f) {
+                        projected.remove_file_or_dir(f);
+                    }
                 }
+
+                self.upload_manifest(&projected)?;
+                self.promote(release)?;
             }
+            self.refresh_touched_files(diff, &state);
+            self.delete_files(&old_files, diff, &state)?;
+            self.forget_files(diff, &state);
+            self.delete_dirs(&old_folders, &state)?;
+        } else {
+            match self.operation_order {
+                OperationOrder::DeleteFirst => {
+                    self.delete_files(&old_files, diff, &state)?;
+                    self.refresh_touched_files(diff, &state);
+                    self.forget_files(diff, &state);
+                    self.delete_dirs(&old_folders, &state)?;
+                    self.make_dirs(&diff.new_folders_shallowest_first(), &state)?;
+                    self.upload_files(diff, &state)?;
+                    self.upload_symlinks(diff, &state)?;
+                },
+                OperationOrder::UploadFirst => {
+                    // 和新内容占用了同一个路径的文件/目录(比如文件变成了目录)，必须先删除才能上传
+                    let overlay_mode = self.config.overlay_mode;
+                    let conflicts = diff.conflict_index();
+                    let (conflicted_files, old_files): (Vec<&String>, Vec<&String>) = old_files.into_iter()
+                        .partition(|f| conflicts.conflicts_with_new(f, overlay_mode));
+                    let (conflicted_folders, old_folders): (Vec<&String>, Vec<&String>) = old_folders.into_iter()
+                        .partition(|f| conflicts.conflicts_with_new(f, overlay_mode));
+
+                    self.delete_files(&conflicted_files, diff, &state)?;
+                    self.delete_dirs(&conflicted_folders, &state)?;
+                    self.make_dirs(&diff.new_folders_shallowest_first(), &state)?;
+                    self.upload_files(diff, &state)?;
+                    self.upload_symlinks(diff, &state)?;
+                    self.refresh_touched_files(diff, &state);
+                    self.delete_files(&old_files, diff, &state)?;
+                    self.forget_files(diff, &state);
+                    self.delete_dirs(&old_folders, &state)?;
+                },
+            }
+        }
 
-            // 同步更新状态(删除剩余的文件)
-            for d in &diff.old_files {
-                if !filtered_old_files.contains(&(&d[..])) {
-                    state.lock().unwrap().get_mut().remove_file_or_dir(d);
-                }
+        self.set_modes(diff, &state)?;
+
+        // 最后上传清单，发布模式下清单已经在promote之前上传过了
+        if diff.has_differences() && self.publishing.is_none() {
+            self.upload_manifest(state.lock().unwrap().get_mut())?;
+        }
+
+        // 执行用户清理指令
+        if diff.has_differences() && !self.config.clean_up.is_empty() {
+            self.execute_single_thread(&self.config.clean_up, &self.variables)?;
+        }
+
+        println!(
+            "旧文件: {}, 旧目录: {}, 新文件: {}, 新目录: {}", 
+            diff.old_files.len(), diff.old_folders.len(),
+            diff.new_files.len(), diff.new_folders.len(),
+        );
+
+        Ok(())
+    }
+
+    /// 删除文件，overlay模式和发布模式下会被新文件覆盖的文件跳过删除步骤，由上传时直接替换状态里的记录
+    fn delete_files(&self, files: &[&String], diff: &Differences, state: &Arc<Mutex<Cell<State>>>) -> AppResult<()> {
+        let filtered_old_files = files
+            .iter()
+            .filter_map(|e| if (self.config.overlay_mode || self.publishing.is_some()) && (diff.new_files.contains(e) || diff.new_symlinks.iter().any(|l| &l.0 == *e)) { None } else { Some(&e[..]) })
+            .collect::<Vec<&str>>();
+        let total = filtered_old_files.len();
+        let done = Arc::new(Mutex::new(0));
+
+        // 命令为空的文件只更新状态
+        let tasks = filtered_old_files.iter()
+            .map(|f| (self.commands_for(f, Operation::DeleteFile).to_owned(), self.file_variables(f)))
+            .collect::<Vec<(Vec<Vec<String>>, VariableReplace)>>();
+
+        let state = state.clone();
+
+        self.execute_multiple_thread(
+            &tasks, 
+            self.config.threads as usize, 
+            Box::new(move |vars| {
+                let mut done = done.lock().unwrap();
+                *done += 1;
+                println!("删除文件({}/{}): {}", done, total, vars.variables.get("path").unwrap());
+            }),
+            Box::new(move |vars| {
+                let path = vars.variables.get("path").unwrap();
+                state.lock().unwrap().get_mut().remove_file_or_dir(path);
+                Ok(())
+            })
+        )
+    }
+
+    /// 只更新修改时间的文件
+    fn refresh_touched_files(&self, diff: &Differences, state: &Arc<Mutex<Cell<State>>>) {
+        for f in &diff.touched_files {
+            let (source, _local_path) = Source::locate(&self.sources, f).unwrap();
+            state.lock().unwrap().get_mut().refresh_modified(f, source, &self.tracking);
+        }
+    }
+
+    /// 只从状态里移除的文件
+    fn forget_files(&self, diff: &Differences, state: &Arc<Mutex<Cell<State>>>) {
+        for f in &diff.forgotten {
+            state.lock().unwrap().get_mut().remove_file_or_dir(f);
+        }
+    }
+
+    /// 删除目录，folders需要按照从深到浅的顺序排列
+    fn delete_dirs(&self, folders: &[&String], state: &Arc<Mutex<Cell<State>>>) -> AppResult<()> {
+        let total = folders.len();
+        let mut done = 0;
+        for f in folders {
+            let vars = self.file_variables(f);
+
+            done += 1;
+            println!("删除目录({}/{}): {}", done, total, f);
+
+            let commands = self.commands_for(f, Operation::DeleteDir);
+            if !commands.is_empty() {
+                self.execute_single_thread(commands, &vars)?;
             }
+
+            state.lock().unwrap().get_mut().remove_file_or_dir(f);
         }
 
-        // 删除目录
-        {
-            let total = &diff.old_folders.len();
-            let mut done = 0;
-            for f in &diff.old_folders {
-                let mut vars = self.variables.to_owned();
-                vars.add("path", f);
-                vars.add("path_", &f.replace("/", "\\"));
+        Ok(())
+    }
 
-                done += 1;
-                println!("删除目录({}/{}): {}", done, total, f);
+    /// 创建目录，folders需要按照从浅到深的顺序排列
+    fn make_dirs(&self, folders: &[&String], state: &Arc<Mutex<Cell<State>>>) -> AppResult<()> {
+        let total = folders.len();
+        let mut done = 0;
+        for f in folders {
+            let vars = self.upload_variables(f);
 
-                if !self.config.delete_dir.is_empty() {
-                    self.execute_single_thread(&self.config.delete_dir, &vars)?;
-                }
+            done += 1;
+            println!("新目录({}/{}): {}", done, total, f);
 
-                state.lock().unwrap().get_mut().remove_file_or_dir(f);
+            let commands = self.commands_for(f, Operation::MakeDir);
+            if !commands.is_empty() {
+                self.execute_single_thread(commands, &vars)?;
             }
+
+            state.lock().unwrap().get_mut().make_dir(f)?;
         }
 
-        // 创建目录
-        {
-            let total = &diff.new_folders.len();
-            let mut done = 0;
-            for f in &diff.new_folders {
-                let mut vars = self.variables.to_owned();
-                vars.add("path", f);
-                vars.add("path_", &f.replace("/", "\\"));
+        Ok(())
+    }
+
+    /// 上传文件
+    fn upload_files(&self, diff: &Differences, state: &Arc<Mutex<Cell<State>>>) -> AppResult<()> {
+        let total = diff.new_files.len();
+        let done = Arc::new(Mutex::new(0));
+
+        // 命令为空的文件只更新状态
+        let tasks = diff.new_files.iter()
+            .map(|f| (self.commands_for(f, Operation::UploadFile).to_owned(), self.upload_variables(f)))
+            .collect::<Vec<(Vec<Vec<String>>, VariableReplace)>>();
+
+        let sources = self.sources.clone();
+        let tracking = self.tracking.clone();
+        let debug = self.options.debug;
+        let state = state.clone();
+
+        self.execute_multiple_thread(
+            &tasks, 
+            self.config.threads as usize, 
+            Box::new(move |vars| {
+                let mut done = done.lock().unwrap();
+                *done += 1;
+                println!("新文件({}/{}): {}", done, total, vars.variables.get("path").unwrap());
+            }),
+            Box::new(move |vars| {
+                let path = vars.variables.get("path").unwrap();
+                let (source, _local_path) = Source::locate(&sources, path).unwrap();
+                state.lock().unwrap().get_mut().add_file(path, source, &tracking, debug)
+            })
+        )
+    }
+
+    /// 上传符号链接
+    fn upload_symlinks(&self, diff: &Differences, state: &Arc<Mutex<Cell<State>>>) -> AppResult<()> {
+        let total = diff.new_symlinks.len();
+        let mut done = 0;
+        for (f, target) in &diff.new_symlinks {
+            // $target是发布目标的名称，链接的目标使用$link-target
+            let mut vars = self.upload_variables(f);
+            vars.add("link-target", target);
+
+            done += 1;
+            println!("符号链接({}/{}): {} -> {}", done, total, f, target);
+
+            let commands = self.commands_for(f, Operation::UploadSymlink);
+            if !commands.is_empty() {
+                self.execute_single_thread(commands, &vars)?;
+            }
+
+            state.lock().unwrap().get_mut().add_symlink(f, target)?;
+        }
+
+        Ok(())
+    }
+
+    /// 更新文件权限
+    fn set_modes(&self, diff: &Differences, state: &Arc<Mutex<Cell<State>>>) -> AppResult<()> {
+        let total = diff.changed_metadata.len();
+        let done = Arc::new(Mutex::new(0));
+
+        // 命令为空的文件只更新状态
+        let tasks = diff.changed_metadata.iter()
+            .map(|f| (self.commands_for(f, Operation::SetMode).to_owned(), self.file_variables(f)))
+            .collect::<Vec<(Vec<Vec<String>>, VariableReplace)>>();
+
+        let sources = self.sources.clone();
+        let tracking = self.tracking.clone();
+        let state = state.clone();
+
+        self.execute_multiple_thread(
+            &tasks, 
+            self.config.threads as usize, 
+            Box::new(move |vars| {
+                let mut done = done.lock().unwrap();
+                *done += 1;
+                println!("更新权限({}/{}): {} {}", done, total, vars.variables.get("path").unwrap(), vars.variables.get("mode").map_or("", |m| m));
+            }),
+            Box::new(move |vars| {
+                let path = vars.variables.get("path").unwrap();
+                let (source, _local_path) = Source::locate(&sources, path).unwrap();
+                state.lock().unwrap().get_mut().update_metadata(path, source, &tracking);
+                Ok(())
+            })
+        )
+    }
+
+    /// 根据状态生成清单文件，并执行manifest.upload命令上传，未配置manifest时什么也不做
+    fn upload_manifest(&self, state: &State) -> AppResult<()> {
+        let config = match &self.config.manifest {
+            Some(config) => config,
+            None => return Ok(()),
+        };
+
+        let file = File::new(&self.variables.apply(&config.file));
+        println!("生成清单文件: {}", file.path());
+
+        let contents = Manifest::new(config, &self.path_mapper)?.render(state, &self.variables, self.config.state_indent as u16);
+        if file.exists() {
+            file.rm()?;
+        }
+        if let Some(parent) = file.parent()? {
+            parent.mkdirs()?;
+        }
+        file.write(&contents)?;
+        let signature = self.signing.sign_file(&file)?;
+
+        if !config.upload.is_empty() {
+            let mut vars = self.variables.to_owned();
+            vars.add("manifest", &file.path());
+            if let Some(signature) = &signature {
+                vars.add("signature", &signature.path());
+            }
+            self.execute_single_thread(&config.upload, &vars)?;
+        }
+
+        Ok(())
+    }
 
-                done += 1;
-                println!("新目录({}/{}): {}", done, total, f);
+    fn load_release_history(&self) -> AppResult<ReleaseHistory> {
+        let publish = self.config.publish.as_ref()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "the publish section is not configured"))?;
 
-                if !self.config.upload_dir.is_empty() {
-                    self.execute_single_thread(&self.config.upload_dir, &vars)?;
+        ReleaseHistory::load(File::new(&self.variables.apply(&publish.history_file)))
+    }
+
+    /// 使用某个版本的名称和暂存前缀作为变量
+    fn release_variables(&self, release: &Release) -> VariableReplace {
+        let mut vars = self.variables.to_owned();
+        vars.add("release", &release.name);
+        vars.add("staging", &release.staging);
+        vars
+    }
+
+    /// 执行promote命令发布暂存前缀下的版本，并清理掉超出keep-releases数量的旧版本
+    fn promote(&self, release: &Release) -> AppResult<()> {
+        let publish = self.config.publish.as_ref().unwrap();
+
+        println!("发布版本: {} ({})", release.name, release.staging);
+        if !publish.promote.is_empty() {
+            self.execute_single_thread(&publish.promote, &self.release_variables(release))?;
+        }
+        self.promoted.store(true, Ordering::SeqCst);
+
+        let mut history = self.load_release_history()?;
+        history.add(release.clone());
+        for expired in history.expired(publish.keep_releases as usize) {
+            self.remove_release(&mut history, &expired)?;
+        }
+
+        history.save()
+    }
+
+    /// 执行remove-release命令清理掉一个版本，并从发布历史里移除
+    fn remove_release(&self, history: &mut ReleaseHistory, release: &Release) -> AppResult<()> {
+        let publish = self.config.publish.as_ref().unwrap();
+
+        println!("清理版本: {} ({})", release.name, release.staging);
+        if !publish.remove_release.is_empty() {
+            self.execute_single_thread(&publish.remove_release, &self.release_variables(release))?;
+        }
+        history.remove(&release.name);
+
+        Ok(())
+    }
+
+    fn list_releases(&self) -> AppResult<()> {
+        let history = self.load_release_history()?;
+        if history.releases.is_empty() {
+            println!("还没有发布过任何版本");
+        }
+
+        for release in &history.releases {
+            println!("{}: {} (time: {})", release.name, release.staging, release.time);
+        }
+
+        Ok(())
+    }
+
+    fn remove_release_by_name(&self, name: &str) -> AppResult<()> {
+        let mut history = self.load_release_history()?;
+        let release = history.find(name)
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("no such release in the publish history: {}", name)))?
+            .clone();
+
+        self.remove_release(&mut history, &release)?;
+        history.save()
+    }
+
+    /// 在状态文件旁边保存一份状态快照，并清理掉多余的旧快照。没有指定--label时，发布模式下使用版本名称作为标签
+    fn take_snapshot(&self, state_file: &File, contents: &[u8]) -> AppResult<()> {
+        let config = match &self.config.snapshots {
+            Some(config) => config,
+            None => return Ok(()),
+        };
+
+        let label = match (&self.options.label, &self.publishing) {
+            (Some(label), _) => label.to_owned(),
+            (None, Some(release)) => release.name.to_owned(),
+            (None, None) => "".to_owned(),
+        };
+
+        let store = SnapshotStore::new(state_file);
+        let snapshot = store.take(contents, &label)?;
+        println!("保存状态快照: {}", snapshot.file.name());
+
+        let pruned = store.prune(config.keep as usize)?;
+        if pruned > 0 {
+            println!("清理旧的状态快照: {}", pruned);
+        }
+
+        Ok(())
+    }
+
+    /// 回滚到一个状态快照：计算当前状态到快照之间的差异，需要上传的文件从快照对应的归档目录里读取
+    fn rollback(&mut self, label: &str) -> AppResult<()> {
+        let archive_dir = self.config.snapshots.as_ref().map_or("", |s| &s.archive_dir[..]);
+        if archive_dir.is_empty() {
+            return Err(Box::new(Error::new(ErrorKind::InvalidInput, "snapshots.archive-dir is not configured, the contents of the snapshots are not available")));
+        }
+
+        let state_file = self.get_state_file();
+        let snapshot = SnapshotStore::new(&state_file).find(label)?
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("no snapshot is labeled: {}", label)))?;
+
+        let mut vars = self.variables.to_owned();
+        vars.add("label", if snapshot.label.is_empty() { label } else { &snapshot.label });
+        let archive = File::new(&vars.apply(archive_dir));
+        if !archive.is_dir() {
+            return Err(Box::new(Error::new(ErrorKind::NotFound, format!("the archive directory of the snapshot is not a dir: {}", archive.path()))));
+        }
+
+        println!("回滚到状态快照: {}", snapshot.file.name());
+        let target = StateFormat::read(&snapshot.file)?;
+        let state = self.load_state_from_file(&state_file)?;
+        let differences = Differences::between(&state.files, &target.files);
+
+        // 归档目录里的文件必须和快照里记录的内容一致，否则拒绝回滚
+        let unavailable = differences.new_files.iter()
+            .chain(differences.changed_metadata.iter())
+            .filter(|f| {
+                let file = archive.append(f).unwrap();
+                match target.files.get_file(f).and_then(|e| e.as_file()) {
+                    Some(expected) => !file.is_file() || file.sha1().map_or(true, |hash| hash != expected.sha1),
+                    None => true,
                 }
+            })
+            .collect::<Vec<&String>>();
 
-                state.lock().unwrap().get_mut().make_dir(f);
+        if !unavailable.is_empty() {
+            for f in &unavailable {
+                println!("归档目录里缺少这个文件或者内容和快照不一致: {}", f);
             }
+            return Err(Box::new(Error::new(ErrorKind::NotFound, format!("{} file(s) of the snapshot are not available in the archive directory: {}", unavailable.len(), archive.path()))));
         }
 
-        // 上传文件
-        {
-            let total = diff.new_files.len();
-            let done = Arc::new(Mutex::new(0));
-    
-            if !self.config.upload_file.is_empty() {
-                let varses = diff.new_files.iter().map(|f| {
-                    let mut vars = self.variables.to_owned();
-                    vars.add("path", f);
-                    vars.add("path_", &f.replace("/", "\\"));
-                    vars
-                }).collect::<Vec<VariableReplace>>();
-    
-                let sourcedir = self.sourcedir.to_owned();
-                let hash_cache = self.hash_cache.clone();
-                let debug = self.options.debug;
-                let state = state.clone();
-    
-                self.execute_multiple_thread(
-                    &self.config.upload_file, 
-                    self.config.threads as usize, 
-                    &varses, 
-                    Box::new(move |vars| {
-                        let mut done = done.lock().unwrap();
-                        *done += 1;
-                        println!("新文件({}/{}): {}", done, total, vars.variables.get("path").unwrap());
-                    }),
-                    Box::new(move |vars| {
-                        let path = vars.variables.get("path").unwrap();
-                        state.lock().unwrap().get_mut().add_file(&path, &sourcedir, &hash_cache, debug);
-                    })
-                )?;
-            } else {
-                for f in &diff.new_files {
-                    let mut done = done.lock().unwrap();
-                    *done += 1;
-                    println!("新文件({}/{}): {}", done, total, f);
-                    state.lock().unwrap().get_mut().add_file(f, &self.sourcedir, &self.hash_cache, self.options.debug);
+        // 从归档目录上传文件
+        let source = SourceConfig {
+            dir: archive.path(),
+            prefix: "".to_owned(),
+            file_filters: Vec::new(),
+            include: Vec::new(),
+            exclude: Vec::new(),
+            ignore_patterns: Vec::new(),
+        };
+        self.sources = Arc::new(vec![Source::new(&source)?]);
+
+        self.apply_differences(&differences, &state_file, state)
+    }
+
+    /// 比较两个状态文件并输出差异，不需要读取配置文件
+    pub fn diff_state_files(old: &str, new: &str, format: &str) -> AppResult<()> {
+        let format = DiffFormat::parse(format)?;
+        let read = |path: &str| -> AppResult<State> {
+            let file = File::new(path);
+            if !file.is_file() {
+                return Err(Box::new(Error::new(ErrorKind::NotFound, format!("the state file is not a file: {}", path))));
+            }
+            StateFormat::read(&file)
+        };
+
+        let old = read(old)?;
+        let new = read(new)?;
+        println!("{}", StateDiff::new(&old, &new).render(&format));
+
+        Ok(())
+    }
+
+    /// 执行查看状态的子命令，不需要加锁
+    fn inspect_state(&self, command: &StateCommand) -> AppResult<()> {
+        if let StateCommand::Diff { old, new, format } = command {
+            return App::diff_state_files(old, new, format);
+        }
+
+        let state = self.load_state_from_file(&self.get_state_file())?;
+
+        match command {
+            StateCommand::Ls(prefix) => {
+                let prefix = prefix.as_deref().unwrap_or("").trim_matches('/');
+                App::list_state(&state, prefix)
+            },
+            StateCommand::Show(path) => {
+                let file = state.files.get_file(path.trim_matches('/'))
+                    .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("the path is not recorded in the state: {}", path)))?;
+                println!("{}", App::describe_state_entry(file, path.trim_matches('/')).pretty(2));
+                Ok(())
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    /// 执行修改状态的子命令
+    fn edit_state(&self, command: &StateCommand) -> AppResult<()> {
+        if command.is_read_only() {
+            return self.inspect_state(command);
+        }
+
+        let state_file = self.get_state_file();
+        let mut state = self.load_state_from_file(&state_file)?;
+
+        let push = match command {
+            StateCommand::Forget { path, push } => {
+                let path = path.trim_matches('/');
+                if !state.files.contains_file(path) {
+                    return Err(Box::new(Error::new(ErrorKind::NotFound, format!("the path is not recorded in the state: {}", path))));
+                }
+
+                state.files.remove_file(path);
+                println!("已从状态里移除: {}", path);
+                *push
+            },
+            StateCommand::Add { path, push } => {
+                let path = path.trim_matches('/');
+                let (source, local_path) = Source::locate(&self.sources, path)
+                    .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("the path does not belong to any source directory: {}", path)))?;
+                let local_file = source.dir.append(&local_path)?;
+                if !local_file.is_file() {
+                    return Err(Box::new(Error::new(ErrorKind::NotFound, format!("the local file is not a file: {}", local_file.path()))));
                 }
+
+                // 补全状态里的上级目录
+                state.make_parent_dirs(path)?;
+
+                state.add_file(path, source, &self.tracking, self.options.debug)?;
+                println!("已把文件记录为已上传: {}", path);
+                *push
+            },
+            _ => unreachable!(),
+        };
+
+        if push {
+            self.save_state_file(true, &state_file, &state)?;
+        } else {
+            if !self.config.use_local_state {
+                return Err(Box::new(Error::new(ErrorKind::InvalidInput, "the local state file is not used (use-local-state is false), use --push to upload the modified state")));
             }
+
+            println!("更新本地状态文件...");
+            self.write_state_file(&state_file, &state)?;
         }
 
-        // 执行用户清理指令
-        if comparer.differences.has_differences() && !self.config.clean_up.is_empty() {
-            self.execute_single_thread(&self.config.clean_up, &self.variables)?;
+        Ok(())
+    }
+
+    /// 列出状态里某个前缀下的所有文件，前缀为空时列出整个状态
+    fn list_state(state: &State, prefix: &str) -> AppResult<()> {
+        fn walk(file: &SimpleFile, path: &str, counts: &mut (usize, usize)) {
+            if let Some(dir) = file.as_dir() {
+                if !path.is_empty() {
+                    println!("{}/", path);
+                    counts.1 += 1;
+                }
+                for f in &dir.files {
+                    let child = if path.is_empty() { f.name.to_owned() } else { path.to_owned() + "/" + &f.name };
+                    walk(f, &child, counts);
+                }
+            } else if let Some(link) = file.as_symlink() {
+                println!("{} -> {}", path, link.target);
+                counts.0 += 1;
+            } else if let Some(data) = file.as_file() {
+                println!("{}  {}  {}", path, data.length, data.sha1);
+                counts.0 += 1;
+            }
         }
 
-        println!(
-            "旧文件: {}, 旧目录: {}, 新文件: {}, 新目录: {}", 
-            diff.old_files.len(), diff.old_folders.len(),
-            diff.new_files.len(), diff.new_folders.len(),
-        );
+        let root = if prefix.is_empty() {
+            SimpleFile::new_directory("", state.files.files.clone())
+        } else {
+            state.files.get_file(prefix)
+                .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("the path is not recorded in the state: {}", prefix)))?
+                .clone()
+        };
+
+        let mut counts = (0, 0);
+        walk(&root, prefix, &mut counts);
+        println!("文件: {}, 目录: {}", counts.0, counts.1);
 
         Ok(())
     }
 
+    /// 状态里记录的一个文件/目录/符号链接的所有信息
+    fn describe_state_entry(file: &SimpleFile, path: &str) -> json::JsonValue {
+        if let Some(data) = file.as_file() {
+            let mut entry = json::object! {
+                path: path,
+                type: "file",
+                length: data.length,
+                hash: data.sha1.to_owned(),
+                modified: data.modified,
+            };
+            if let Some(modified_ns) = data.modified_ns {
+                entry["modified-ns"] = modified_ns.into();
+            }
+            if let Some(mode) = data.mode {
+                entry["mode"] = format!("{:o}", mode).into();
+            }
+            if let Some(uid) = data.uid {
+                entry["uid"] = uid.into();
+            }
+            if let Some(gid) = data.gid {
+                entry["gid"] = gid.into();
+            }
+            entry
+        } else if let Some(link) = file.as_symlink() {
+            json::object! { path: path, type: "symlink", link: link.target.to_owned() }
+        } else {
+            let children = file.as_dir().map_or(0, |d| d.files.len());
+            json::object! { path: path, type: "dir", children: children }
+        }
+    }
+
     fn test_filter(&self) -> AppResult<()> {
-        fn walk(directory: &File, base: &File, filter: &RuleFilter) -> AppResult<()> {
+        fn walk(directory: &File, source: &Source, symlinks: &SymlinkPolicy) -> AppResult<()> {
             for f in directory.files()? {
                 let f = f?;
-                let relative_path = f.relativized_by(base);
-                let matched = filter.test_all(&relative_path, true);
-                if matched {
-                    println!("matched: {}", relative_path);
+                let kind = symlinks.classify(&f, &source.dir)?;
+                if let LocalKind::None = kind {
+                    continue;
                 }
 
-                if f.is_dir() {
-                    walk(&f, base, filter)?;
+                let relative_path = f.relativized_by(&source.dir);
+                let is_dir = matches!(kind, LocalKind::Dir);
+                let (selected, reason) = source.selector.explain(&relative_path, is_dir)?;
+                println!("{}: {} ({})", if selected { "selected" } else { "excluded" }, source.to_state_path(&relative_path), reason);
+
+                if is_dir {
+                    walk(&f, source, symlinks)?;
                 }
             }
 
             Ok(())
         }
 
-        walk(&self.sourcedir, &self.sourcedir, &self.file_filter)?;
+        for source in self.sources.iter() {
+            walk(&source.dir, source, &self.symlinks)?;
+        }
+
+        Ok(())
+    }
+
+    fn test_mappings(&self) -> AppResult<()> {
+        fn walk(directory: &File, source: &Source, mapper: &PathMapper, symlinks: &SymlinkPolicy) -> AppResult<()> {
+            for f in directory.files()? {
+                let f = f?;
+                let kind = symlinks.classify(&f, &source.dir)?;
+                if let LocalKind::None = kind {
+                    continue;
+                }
+
+                let relative_path = f.relativized_by(&source.dir);
+                let is_dir = matches!(kind, LocalKind::Dir);
+                if source.filter(&relative_path, is_dir)? {
+                    let path = source.to_state_path(&relative_path);
+                    println!("{} -> {}", path, mapper.map(&path));
+                }
+
+                if is_dir {
+                    walk(&f, source, mapper, symlinks)?;
+                }
+            }
+
+            Ok(())
+        }
+
+        for source in self.sources.iter() {
+            walk(&source.dir, source, &self.path_mapper, &self.symlinks)?;
+        }
+
+        Ok(())
+    }
+
+    /// 先执行一次完整的同步，然后监视源目录，每当有文件变动时只同步发生了变动的子目录，直到收到SIGINT
+    pub fn watch(&mut self) -> AppResult<()> {
+        let running = Arc::new(AtomicBool::new(true));
+        {
+            let running = running.clone();
+            ctrlc::set_handler(move || running.store(false, Ordering::SeqCst))?;
+        }
+
+        // 要在第一次同步之前开始监视，避免漏掉同步期间发生的变动
+        let watcher = FileWatcher::new(&self.sources.iter().map(|s| &s.dir).collect::<Vec<&File>>())?;
+        let debounce = Duration::from_millis(self.config.watch_debounce);
+
+        let state_file = self.get_state_file();
+        let state = Arc::new(Mutex::new(Cell::new(self.load_state_from_file(&state_file)?)));
+        let differences = self.compare_files(state.lock().unwrap().get_mut())?;
+        let mut has_differences = differences.has_state_changes();
+
+        let result = self.execute_operations(&differences, state.clone());
+
+        if result.is_err() {
+            println!("更新状态时出现错误，保存状态文件");
+            self.save_state_file(has_differences, &state_file, state.lock().unwrap().get_mut())?;
+            return result;
+        }
+
+        for source in self.sources.iter() {
+            println!("正在监视文件变动(按Ctrl+C退出): {}", source.dir.path());
+        }
+
+        loop {
+            let changes = match watcher.wait_for_changes(debounce, &running) {
+                Ok(Some(changes)) => changes,
+                Ok(None) => break,
+                Err(e) => {
+                    // 之前已经同步了的文件要先记录下来，不然下次运行时会被重新上传
+                    println!("监视文件变动时出现错误，保存状态文件");
+                    self.save_state_file(has_differences, &state_file, state.lock().unwrap().get_mut())?;
+                    return Err(e);
+                },
+            };
+
+            if self.options.debug {
+                println!("检测到{}个文件变动", changes.len());
+            }
+
+            // 文件内容可能已经变了，不能再使用之前缓存的hash和.uploadignore
+            for source in self.sources.iter() {
+                source.hash_cache.clear();
+                source.selector.ignore_filter.clear();
+            }
+
+            let differences = match self.compare_changed_files(state.lock().unwrap().get_mut(), &changes) {
+                Ok(differences) => differences,
+                Err(e) => {
+                    println!("计算文件差异时出现错误: {}", e);
+                    continue;
+                },
+            };
+            has_differences |= differences.has_state_changes();
+
+            // 失败的文件不会被记录到状态中，下次对比时会被重新同步
+            if let Err(e) = self.execute_operations(&differences, state.clone()) {
+                println!("同步时出现错误: {}", e);
+            }
+        }
+
+        println!("正在退出监视模式");
+
+        // 更新状态文件
+        self.save_state_file(has_differences, &state_file, state.lock().unwrap().get_mut())?;
 
         Ok(())
     }
 
     pub fn main(&mut self) -> AppResult<()> {
+        // 查看状态的子命令在获取锁之前执行，上传正在进行的时候也可以查看
+        if let AppCommand::State(command) = &self.options.command {
+            if command.is_read_only() {
+                return self.inspect_state(command);
+            }
+        }
+
+        if !self.modifies_state() {
+            return self.run();
+        }
+
+        if self.options.force_unlock {
+            self.force_unlock()?;
+        }
+        let guard = self.acquire_lock()?;
+
+        // 出错或者panic的时候也要释放远端锁和本地锁
+        let result = panic::catch_unwind(AssertUnwindSafe(|| self.run()));
+        let released = self.release_lock(guard);
+        match result {
+            Ok(result) => result?,
+            Err(payload) => panic::resume_unwind(payload),
+        }
+        released
+    }
+
+    /// 当前的子命令是否会修改状态，只有这些子命令需要加锁
+    fn modifies_state(&self) -> bool {
+        if self.options.test_filter || self.options.test_mappings {
+            return false;
+        }
+
+        match &self.options.command {
+            AppCommand::State(command) => !command.is_read_only(),
+            command => matches!(command, AppCommand::Sync | AppCommand::Watch | AppCommand::Rollback(_) | AppCommand::RemoveRelease(_)),
+        }
+    }
+
+    /// 依次获取本地锁和远端锁，远端锁获取失败时会释放已经获取到的本地锁
+    fn acquire_lock(&self) -> AppResult<Option<StateLockGuard>> {
+        let guard = match &self.state_lock {
+            Some(lock) => Some(lock.acquire(self.config.lock_timeout)?),
+            None => None,
+        };
+
+        if !self.config.acquire_lock.is_empty() {
+            println!("获取远端锁...");
+            if let Err(e) = self.execute_single_thread(&self.config.acquire_lock, &self.lock_variables()) {
+                if let Some(guard) = guard {
+                    guard.release()?;
+                }
+                return Err(e);
+            }
+        }
+
+        Ok(guard)
+    }
+
+    /// 释放远端锁和本地锁，其中一个失败时也会继续释放另一个
+    fn release_lock(&self, guard: Option<StateLockGuard>) -> AppResult<()> {
+        let remote = if !self.config.release_lock.is_empty() {
+            println!("释放远端锁...");
+            self.execute_single_thread(&self.config.release_lock, &self.lock_variables())
+        } else {
+            Ok(())
+        };
+
+        if let Some(guard) = guard {
+            guard.release()?;
+        }
+
+        remote
+    }
+
+    /// 强制删除别人持有的锁
+    fn force_unlock(&self) -> AppResult<()> {
+        println!("强制解锁");
+
+        if let Some(lock) = &self.state_lock {
+            lock.force_unlock()?;
+        }
+
+        if !self.config.release_lock.is_empty() {
+            self.execute_single_thread(&self.config.release_lock, &self.lock_variables())?;
+        }
+
+        Ok(())
+    }
+
+    fn lock_variables(&self) -> VariableReplace {
+        let mut vars = self.variables.to_owned();
+        vars.add("lock-owner", &StateLock::current_owner());
+        vars.add("lock-timeout", &self.config.lock_timeout.to_string());
+        vars
+    }
+
+    fn run(&mut self) -> AppResult<()> {
         if self.options.test_filter {
             self.test_filter()?;
             return Ok(());
         }
 
+        if self.options.test_mappings {
+            self.test_mappings()?;
+            return Ok(());
+        }
+
+        match &self.options.command {
+            AppCommand::Watch if self.publishing.is_some() => {
+                return Err(Box::new(Error::new(ErrorKind::InvalidInput, "the watch command can not be used with the publish section")));
+            },
+            AppCommand::Watch => return self.watch(),
+            AppCommand::ListReleases => return self.list_releases(),
+            AppCommand::RemoveRelease(name) => return self.remove_release_by_name(name),
+            AppCommand::VerifySignature(file) => {
+                self.signing.verify_file(&File::new(file))?;
+                println!("签名验证通过: {}", file);
+                return Ok(());
+            },
+            AppCommand::Rollback(label) => {
+                let label = label.to_owned();
+                return self.rollback(&label);
+            },
+            AppCommand::State(command) => {
+                let command = command.clone();
+                return self.edit_state(&command);
+            },
+            AppCommand::Sync => {},
+        }
+
         let state_file = self.get_state_file();
-        let state = Arc::new(Mutex::new(Cell::new(self.load_state_from_file(&state_file)?)));
-        let comparer = self.compare_files(state.lock().unwrap().get_mut())?;
+        let state = self.load_state_from_file(&state_file)?;
+        let differences = self.compare_files(&state)?;
+
+        self.apply_differences(&differences, &state_file, state)
+    }
+
+    /// 执行所有的远端操作并保存状态文件
+    fn apply_differences(&self, differences: &Differences, state_file: &File, state: State) -> AppResult<()> {
+        let state = Arc::new(Mutex::new(Cell::new(state)));
 
         // 执行远端读写操作
-        let result = self.execute_operations(&comparer, state.clone());
+        let result = self.execute_operations(differences, state.clone());
         
         if result.is_err() {
+            // 发布模式下，没有发布成功的版本不能记录到状态里
+            if self.publishing.is_some() && !self.promoted.load(Ordering::SeqCst) {
+                println!("发布版本时出现错误，不更新状态文件");
+                return result;
+            }
+
             println!("更新状态时出现错误，保存状态文件");
         }
 
         // 更新状态文件
-        self.save_state_file(&comparer, &state_file, state.lock().unwrap().get_mut())?;
+        self.save_state_file(differences.has_state_changes(), state_file, state.lock().unwrap().get_mut())?;
 
         result?;
 
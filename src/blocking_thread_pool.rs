@@ -1,6 +1,8 @@
 use std::cell::Cell;
 use std::cell::UnsafeCell;
 use std::error::Error;
+use std::panic;
+use std::panic::AssertUnwindSafe;
 use std::sync::Arc;
 use std::sync::Mutex;
 use std::sync::mpsc;
@@ -23,6 +25,8 @@ pub struct Worker {
     pub thread: Option<JoinHandle<()>>,
     pub exited_flag: bool,
     pub busy: bool,
+    /// 执行任务时出现过错误
+    pub failed: bool,
     pub on_error: Box<dyn Fn(Box<dyn std::error::Error + Send>) + Send>,
 }
 
@@ -40,6 +44,7 @@ impl Worker {
             exited_flag: false,
             thread: None,
             busy: false,
+            failed: false,
             on_error,
         }));
 
@@ -58,11 +63,17 @@ impl Worker {
             
             match msg {
                 WorkerMessage::Task(task) => {
+                    // 出错之后丢弃剩下的任务，但是还要继续接收消息，不然execute和close_and_wait会一直阻塞
+                    if self.failed {
+                        continue;
+                    }
+
                     self.busy = true;
-                    let result = task();
+                    // 任务panic时当作出错处理，由调用者正常返回错误，不然工作线程会直接退出，持有的锁也得不到释放
+                    let result = panic::catch_unwind(AssertUnwindSafe(task)).unwrap_or_else(|payload| Err(panic_error(payload)));
                     if result.is_err() {
                         (self.on_error)(result.err().unwrap());
-                        return;
+                        self.failed = true;
                     }
                     self.busy = false;
                 }
@@ -92,6 +103,20 @@ impl Worker {
     }
 }
 
+/// 把panic的内容转换成错误
+fn panic_error(payload: Box<dyn std::any::Any + Send>) -> Box<dyn Error + Send> {
+    let message = if let Some(s) = payload.downcast_ref::<&str>() {
+        s.to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.to_owned()
+    } else {
+        "unknown panic".to_owned()
+    };
+
+    let error: Box<dyn Error + Send + Sync> = format!("a task panicked: {}", message).into();
+    error
+}
+
 pub struct BlockingThreadPool {
     workers: Vec<Arc<UnsafeCell<Worker>>>,
     sender: mpsc::SyncSender<WorkerMessage>,
@@ -161,4 +186,32 @@ impl Drop for BlockingThreadPool {
             self.close_and_wait().unwrap();
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+
+    #[test]
+    fn panicking_task_is_returned_as_error() {
+        let mut pool = BlockingThreadPool::new(2);
+        let finished = Arc::new(AtomicUsize::new(0));
+
+        // 任务比线程多，工作线程panic之后不能卡住
+        for i in 0..8 {
+            let finished = finished.clone();
+            pool.execute(move || {
+                if i == 1 {
+                    panic!("boom");
+                }
+                finished.fetch_add(1, Ordering::SeqCst);
+                Ok(())
+            });
+        }
+
+        let error = pool.close_and_wait().err().unwrap();
+        assert!(error.to_string().contains("boom"));
+        assert!(finished.load(Ordering::SeqCst) >= 1);
+    }
+}
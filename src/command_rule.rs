@@ -0,0 +1,85 @@
+use crate::AppResult;
+use crate::app_config::CommandRuleConfig;
+use crate::file::File;
+use crate::rule_filter::RuleFilter;
+
+/// 可以被rules覆盖的文件操作
+pub enum Operation {
+    DeleteFile,
+    DeleteDir,
+    UploadFile,
+    MakeDir,
+    UploadSymlink,
+    SetMode,
+}
+
+/// 针对匹配的文件覆盖默认命令的规则
+pub struct CommandRule {
+    pub filter: RuleFilter,
+    pub config: CommandRuleConfig,
+}
+
+impl CommandRule {
+    pub fn new(config: &CommandRuleConfig) -> AppResult<CommandRule> {
+        Ok(CommandRule { filter: RuleFilter::new(&config.patterns)?, config: config.clone() })
+    }
+
+    /// 获取这条规则对某个操作的命令，没有覆盖这个操作时返回None
+    pub fn get_commands(&self, operation: &Operation) -> Option<&Vec<Vec<String>>> {
+        match operation {
+            Operation::DeleteFile => self.config.delete_file.as_ref(),
+            Operation::DeleteDir => self.config.delete_dir.as_ref(),
+            Operation::UploadFile => self.config.upload_file.as_ref(),
+            Operation::MakeDir => self.config.upload_dir.as_ref(),
+            Operation::UploadSymlink => self.config.upload_symlink.as_ref(),
+            Operation::SetMode => self.config.set_mode.as_ref(),
+        }
+    }
+
+    /// 在多条规则中找到第一条匹配path的规则，并返回它对某个操作的命令
+    ///
+    /// 匹配的规则没有覆盖这个操作时返回None，此时应该使用默认的命令
+    ///
+    /// file: path对应的本地文件，用于判断文件属性条件(比如@size>100M)，本地文件不存在时属性条件不适用
+    pub fn find_commands<'a>(rules: &'a [CommandRule], path: &str, file: Option<&File>, operation: &Operation) -> Option<&'a Vec<Vec<String>>> {
+        rules.iter()
+            .find(|r| r.filter.test_all(path, file, false))
+            .and_then(|r| r.get_commands(operation))
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TempDir;
+
+    fn rule(pattern: &str, upload: &str) -> CommandRule {
+        CommandRule::new(&CommandRuleConfig {
+            patterns: vec![pattern.to_owned()],
+            delete_file: None,
+            delete_dir: None,
+            upload_file: Some(vec![vec![upload.to_owned()]]),
+            upload_dir: None,
+            upload_symlink: None,
+            set_mode: None,
+        }).unwrap()
+    }
+
+    #[test]
+    fn attribute_rules_use_the_local_file() {
+        let temp = TempDir::new("rules");
+        let big = temp.file("big.bin");
+        big.write_atomically(vec![0u8; 4096]).unwrap();
+        let small = temp.file("small.bin");
+        small.write_atomically("a").unwrap();
+
+        let rules = vec![rule("@size>1K", "multipart"), rule("\\.bin$", "normal")];
+        let upload = |path: &str, file: Option<&File>| CommandRule::find_commands(&rules, path, file, &Operation::UploadFile).map(|c| c[0][0].to_owned());
+
+        assert_eq!(upload("big.bin", Some(&big)).as_deref(), Some("multipart"));
+        assert_eq!(upload("small.bin", Some(&small)).as_deref(), Some("normal"));
+        // 本地文件已经不存在时属性条件不适用
+        assert_eq!(upload("big.bin", None).as_deref(), Some("normal"));
+        assert_eq!(upload("other.txt", None), None);
+        assert!(CommandRule::find_commands(&rules, "big.bin", Some(&big), &Operation::DeleteFile).is_none());
+    }
+}
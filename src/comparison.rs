@@ -0,0 +1,140 @@
+use std::io::Error;
+use std::io::ErrorKind;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+use crate::AppResult;
+use crate::file::File;
+use crate::hash_cache::HashCache;
+use crate::simple_file::FileData;
+
+/// 文件对比模式
+pub enum ComparisonMode {
+    /// 修改时间一样时认为文件没有变化，否则对比文件的hash
+    Fast,
+    /// 不看修改时间，总是对比文件的hash
+    Hash,
+    /// 总是对比文件的hash，并且更新状态里内容没有变化、但修改时间变化了的文件的修改时间
+    Paranoid,
+}
+
+/// 文件对比的一个阶段
+pub enum Stage {
+    /// 文件大小不一样时，文件一定发生了变化
+    Length,
+    /// 修改时间一样时，认为文件没有变化
+    Mtime,
+    /// 对比文件的hash
+    Hash,
+}
+
+/// 分阶段的文件对比，按顺序执行每一个阶段，任何一个阶段得出结论后就不再执行后面的阶段
+///
+/// 所有的阶段都无法得出结论时，认为文件发生了变化
+pub struct StagedComparison {
+    stages: Vec<Stage>,
+    tolerance_ns: u64,
+    /// 每个阶段得出结论的次数：(没有变化, 发生了变化)
+    hits: Vec<(AtomicUsize, AtomicUsize)>,
+}
+
+impl ComparisonMode {
+    pub fn parse(text: &str) -> std::io::Result<ComparisonMode> {
+        match text {
+            "fast" => Ok(ComparisonMode::Fast),
+            "hash" => Ok(ComparisonMode::Hash),
+            "paranoid" => Ok(ComparisonMode::Paranoid),
+            _ => Err(Error::new(ErrorKind::InvalidInput, format!("unknown value of comparison: {}", text))),
+        }
+    }
+
+    /// 没有配置comparison-stages时使用的对比阶段
+    pub fn default_stages(&self) -> Vec<String> {
+        let stages: &[&str] = match self {
+            ComparisonMode::Fast => &["length", "mtime", "hash"],
+            ComparisonMode::Hash => &["length", "hash"],
+            ComparisonMode::Paranoid => &["hash"],
+        };
+
+        stages.iter().map(|s| s.to_string()).collect()
+    }
+
+    /// 内容没有变化的文件是否需要更新状态里记录的修改时间
+    pub fn refreshes_modified(&self) -> bool {
+        match self {
+            ComparisonMode::Fast | ComparisonMode::Paranoid => true,
+            ComparisonMode::Hash => false,
+        }
+    }
+}
+
+impl Stage {
+    fn parse(name: &str) -> AppResult<Stage> {
+        match name {
+            "length" => Ok(Stage::Length),
+            "mtime" => Ok(Stage::Mtime),
+            "hash" => Ok(Stage::Hash),
+            _ => Err(Box::new(Error::new(ErrorKind::InvalidInput, format!("unknown stage of comparison-stages: {}", name)))),
+        }
+    }
+
+    fn name(&self) -> &str {
+        match self {
+            Stage::Length => "length",
+            Stage::Mtime => "mtime",
+            Stage::Hash => "hash",
+        }
+    }
+
+    /// 返回Some(true)表示文件没有变化，Some(false)表示文件发生了变化，None表示需要交给下一个阶段
+    fn compare(&self, remote: &FileData, local: &File, path: &str, tolerance_ns: u64, hash_cache: &HashCache, debug_mode: bool) -> Option<bool> {
+        match self {
+            Stage::Length => match local.length() {
+                Ok(length) if length != remote.length => Some(false),
+                _ => None,
+            },
+            Stage::Mtime => match local.modified_nanos() {
+                Ok(modified) if remote.modified_matches(modified, tolerance_ns) => Some(true),
+                _ => None,
+            },
+            Stage::Hash => Some(remote.sha1 == hash_cache.get_hash(path, debug_mode)),
+        }
+    }
+}
+
+impl StagedComparison {
+    /// stages: 每个阶段的名称<br/>
+    /// tolerance_ns: 对比修改时间时允许的误差(纳秒)
+    pub fn new(stages: &[String], tolerance_ns: u64) -> AppResult<StagedComparison> {
+        let mut parsed = Vec::<Stage>::new();
+        for stage in stages {
+            parsed.push(Stage::parse(stage)?);
+        }
+
+        let hits = parsed.iter().map(|_| (AtomicUsize::new(0), AtomicUsize::new(0))).collect();
+
+        Ok(StagedComparison { stages: parsed, tolerance_ns, hits })
+    }
+
+    /// 判断本地文件和状态里记录的文件是否一样
+    pub fn compare(&self, remote: &FileData, local: &File, path: &str, hash_cache: &HashCache, debug_mode: bool) -> bool {
+        for (stage, (same, changed)) in self.stages.iter().zip(&self.hits) {
+            if let Some(result) = stage.compare(remote, local, path, self.tolerance_ns, hash_cache, debug_mode) {
+                if result { same } else { changed }.fetch_add(1, Ordering::Relaxed);
+                return result;
+            }
+        }
+
+        false
+    }
+
+    /// 输出每个阶段得出结论的次数，并清空计数
+    pub fn report(&self) {
+        for (stage, (same, changed)) in self.stages.iter().zip(&self.hits) {
+            println!(
+                "comparison stage {}: {} unchanged, {} changed",
+                stage.name(), same.swap(0, Ordering::Relaxed), changed.swap(0, Ordering::Relaxed),
+            );
+        }
+    }
+}
\ No newline at end of file
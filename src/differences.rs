@@ -1,8 +1,46 @@
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Result;
+
+use crate::simple_file::DirData;
+use crate::simple_file::SimpleFile;
+use crate::utils::get_dirname;
+
+/// 执行各种操作的顺序
+pub enum OperationOrder {
+    /// 先删除旧的文件和目录，再创建目录和上传文件
+    DeleteFirst,
+    /// 先创建目录和上传文件，最后才删除旧的文件和目录，同步过程中远端不会出现文件缺失的情况
+    UploadFirst,
+}
+
+impl OperationOrder {
+    pub fn parse(text: &str) -> Result<OperationOrder> {
+        match text {
+            "delete-first" => Ok(OperationOrder::DeleteFirst),
+            "upload-first" => Ok(OperationOrder::UploadFirst),
+            _ => Err(Error::new(ErrorKind::InvalidInput, format!("unknown value of operation-order: {}", text))),
+        }
+    }
+}
+
 pub struct Differences {
     pub old_files: Vec<String>,
     pub old_folders: Vec<String>,
     pub new_files: Vec<String>,
     pub new_folders: Vec<String>,
+    /// 需要上传的符号链接，以及链接的目标
+    pub new_symlinks: Vec<(String, String)>,
+    /// 内容没有变化、只有权限或者所有者发生了变化的文件
+    pub changed_metadata: Vec<String>,
+    /// 内容没有变化、只需要更新状态里记录的修改时间的文件
+    pub touched_files: Vec<String>,
+    /// 只从状态里移除，不执行任何命令的文件和目录
+    pub forgotten: Vec<String>,
+    /// 状态里已经记录了、但是现在被过滤器排除掉的文件
+    pub excluded: Vec<String>,
 }
 
 impl Differences {
@@ -12,13 +50,364 @@ impl Differences {
             old_folders: Vec::new(), 
             new_files: Vec::new(), 
             new_folders: Vec::new(),
+            new_symlinks: Vec::new(),
+            changed_metadata: Vec::new(),
+            touched_files: Vec::new(),
+            forgotten: Vec::new(),
+            excluded: Vec::new(),
+        }
+    }
+
+    /// 计算从一个状态变成另一个状态所需要的操作，内容发生变化的文件会同时出现在old_files和new_files里
+    /// 
+    /// old: 当前的状态<br/>
+    /// new: 目标状态
+    pub fn between(old: &DirData, new: &DirData) -> Differences {
+        fn add_old(file: &SimpleFile, path: &str, differences: &mut Differences) {
+            if let Some(dir) = file.as_dir() {
+                for f in &dir.files {
+                    add_old(f, &(path.to_owned() + "/" + &f.name), differences);
+                }
+                differences.old_folders.push(path.to_owned());
+            } else {
+                differences.old_files.push(path.to_owned());
+            }
         }
+
+        fn add_new(file: &SimpleFile, path: &str, differences: &mut Differences) {
+            if let Some(dir) = file.as_dir() {
+                differences.new_folders.push(path.to_owned());
+                for f in &dir.files {
+                    add_new(f, &(path.to_owned() + "/" + &f.name), differences);
+                }
+            } else if let Some(link) = file.as_symlink() {
+                differences.new_symlinks.push((path.to_owned(), link.target.to_owned()));
+            } else {
+                differences.new_files.push(path.to_owned());
+            }
+        }
+
+        fn walk(old: &DirData, new: &DirData, parent: &str, differences: &mut Differences) {
+            let same_type = |a: &SimpleFile, b: &SimpleFile| a.is_file() == b.is_file() && a.is_dir() == b.is_dir() && a.is_symlink() == b.is_symlink();
+
+            // 一个目录下可能有大量的文件，按名称建立索引，避免逐个查找
+            let old_index = old.files.iter().map(|f| (&f.name[..], f)).collect::<HashMap<&str, &SimpleFile>>();
+            let new_index = new.files.iter().map(|f| (&f.name[..], f)).collect::<HashMap<&str, &SimpleFile>>();
+
+            for o in &old.files {
+                let path = if parent.is_empty() { o.name.to_owned() } else { parent.to_owned() + "/" + &o.name };
+                match new_index.get(&o.name[..]) {
+                    Some(n) if same_type(o, n) => {
+                        if let (Some(od), Some(nd)) = (o.as_dir(), n.as_dir()) {
+                            walk(od, nd, &path, differences);
+                        }
+                    },
+                    _ => add_old(o, &path, differences),
+                }
+            }
+
+            for n in &new.files {
+                let path = if parent.is_empty() { n.name.to_owned() } else { parent.to_owned() + "/" + &n.name };
+                match old_index.get(&n.name[..]) {
+                    Some(o) if same_type(o, n) => {
+                        if let (Some(of), Some(nf)) = (o.as_file(), n.as_file()) {
+                            if of.length != nf.length || of.sha1 != nf.sha1 {
+                                differences.old_files.push(path.to_owned());
+                                differences.new_files.push(path);
+                            } else if of.mode != nf.mode || of.uid != nf.uid || of.gid != nf.gid {
+                                differences.changed_metadata.push(path);
+                            }
+                        } else if let (Some(ol), Some(nl)) = (o.as_symlink(), n.as_symlink()) {
+                            if ol.target != nl.target {
+                                differences.old_files.push(path.to_owned());
+                                differences.new_symlinks.push((path, nl.target.to_owned()));
+                            }
+                        }
+                    },
+                    _ => add_new(n, &path, differences),
+                }
+            }
+        }
+
+        let mut differences = Differences::new();
+        walk(old, new, "", &mut differences);
+        differences
     }
 
     pub fn has_differences(&self) -> bool {
         self.old_files.len() +
         self.old_folders.len() +
         self.new_files.len() +
-        self.new_folders.len() > 0
+        self.new_folders.len() +
+        self.new_symlinks.len() +
+        self.changed_metadata.len() +
+        self.forgotten.len() > 0
+    }
+
+    /// 是否需要更新状态文件(包括只需要更新修改时间的文件)
+    pub fn has_state_changes(&self) -> bool {
+        self.has_differences() || !self.touched_files.is_empty()
+    }
+
+    /// 按照从深到浅的顺序返回需要删除的目录，子目录总是在上级目录之前被删除
+    pub fn old_folders_deepest_first(&self) -> Vec<&String> {
+        let mut folders = self.old_folders.iter().collect::<Vec<&String>>();
+        folders.sort_by_key(|f| std::cmp::Reverse(f.matches('/').count()));
+        folders
+    }
+
+    /// 按照从浅到深的顺序返回需要创建的目录，上级目录总是在子目录之前被创建
+    pub fn new_folders_shallowest_first(&self) -> Vec<&String> {
+        let mut folders = self.new_folders.iter().collect::<Vec<&String>>();
+        folders.sort_by_key(|f| f.matches('/').count());
+        folders
+    }
+
+    /// 按照从浅到深的顺序返回上传新内容时需要用到的所有目录，包括新目录和新文件所在的每一级上级目录
+    pub fn upload_folders_shallowest_first(&self) -> Vec<String> {
+        let mut folders = self.new_folders.to_owned();
+        let mut added = self.new_folders.iter().map(|f| &f[..]).collect::<HashSet<&str>>();
+        for path in self.new_files.iter().chain(self.new_symlinks.iter().map(|l| &l.0)) {
+            let mut current = get_dirname(path);
+            while let Some(dir) = current {
+                if added.insert(dir) {
+                    folders.push(dir.to_owned());
+                }
+                current = get_dirname(dir);
+            }
+        }
+
+        folders.sort_by_key(|f| f.matches('/').count());
+        folders
+    }
+
+    /// 建立用于判断删除操作是否和上传冲突的索引，见ConflictIndex::conflicts_with_new
+    pub fn conflict_index(&self) -> ConflictIndex<'_> {
+        ConflictIndex::new(self)
+    }
+
+    /// 合并另一个差异的所有内容，多个源目录可能会共用同一个上级目录，所以新目录需要去重
+    pub fn merge(&mut self, other: Differences) {
+        self.old_files.extend(other.old_files);
+        self.old_folders.extend(other.old_folders);
+        self.new_files.extend(other.new_files);
+        self.new_symlinks.extend(other.new_symlinks);
+        self.changed_metadata.extend(other.changed_metadata);
+        self.touched_files.extend(other.touched_files);
+        self.forgotten.extend(other.forgotten);
+        self.excluded.extend(other.excluded);
+
+        for folder in other.new_folders {
+            if !self.new_folders.contains(&folder) {
+                self.new_folders.push(folder);
+            }
+        }
+    }
+}
+
+/// 需要上传的内容所占用的路径，每次同步只建立一次，删除大量文件时不需要每次都遍历所有的上传
+pub struct ConflictIndex<'a> {
+    /// 需要上传的文件和符号链接
+    uploads: HashSet<&'a str>,
+    /// 变成了文件或者符号链接的旧目录
+    replaced_dirs: HashSet<&'a str>,
+    /// 新目录，以及新目录和需要上传的内容的每一级上级目录
+    occupied_dirs: HashSet<&'a str>,
+}
+
+impl<'a> ConflictIndex<'a> {
+    pub fn new(differences: &'a Differences) -> ConflictIndex<'a> {
+        let uploads = differences.new_files.iter().map(|f| &f[..])
+            .chain(differences.new_symlinks.iter().map(|l| &l.0[..]))
+            .collect::<HashSet<&str>>();
+
+        let replaced_dirs = differences.old_folders.iter()
+            .map(|d| &d[..])
+            .filter(|d| uploads.contains(d))
+            .collect::<HashSet<&str>>();
+
+        let mut occupied_dirs = HashSet::<&str>::new();
+        for path in differences.new_folders.iter().map(|d| &d[..]).chain(uploads.iter().copied()) {
+            let mut current = get_dirname(path);
+            while let Some(dir) = current {
+                // 已经添加过的目录，它的每一级上级目录也都已经添加过了
+                if !occupied_dirs.insert(dir) {
+                    break;
+                }
+                current = get_dirname(dir);
+            }
+        }
+        occupied_dirs.extend(differences.new_folders.iter().map(|d| &d[..]));
+
+        ConflictIndex { uploads, replaced_dirs, occupied_dirs }
     }
-}
\ No newline at end of file
+
+    /// 判断一个需要删除的文件/目录是否和需要上传的内容占用了同一个路径，这样的文件/目录必须在上传之前删除
+    /// 
+    /// path: 需要删除的文件/目录<br/>
+    /// overlay_mode: 开启时，被新文件覆盖的旧文件不算冲突
+    pub fn conflicts_with_new(&self, path: &str, overlay_mode: bool) -> bool {
+        // 旧的目录变成了文件，这个目录以及里面的所有内容都需要先删除
+        let mut current = Some(path);
+        while let Some(dir) = current {
+            if self.replaced_dirs.contains(dir) {
+                return true;
+            }
+            current = get_dirname(dir);
+        }
+
+        // 旧的文件变成了目录
+        if self.occupied_dirs.contains(path) {
+            return true;
+        }
+
+        // 非overlay模式下，修改过的文件需要先删除再上传
+        !overlay_mode && self.uploads.contains(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paths(folders: Vec<&String>) -> Vec<&str> {
+        folders.into_iter().map(|f| &f[..]).collect()
+    }
+
+    #[test]
+    fn folders_are_ordered_by_depth() {
+        let mut differences = Differences::new();
+        differences.old_folders = vec!["a".to_owned(), "a/b/c".to_owned(), "d".to_owned(), "a/b".to_owned()];
+        differences.new_folders = vec!["x/y/z".to_owned(), "x".to_owned(), "x/y".to_owned(), "w".to_owned()];
+
+        assert_eq!(paths(differences.old_folders_deepest_first()), vec!["a/b/c", "a/b", "a", "d"]);
+        assert_eq!(paths(differences.new_folders_shallowest_first()), vec!["x", "w", "x/y", "x/y/z"]);
+    }
+
+    #[test]
+    fn upload_folders_include_parents() {
+        let mut differences = Differences::new();
+        differences.new_folders = vec!["n".to_owned()];
+        differences.new_files = vec!["a/b/file".to_owned(), "a/other".to_owned(), "top".to_owned()];
+        differences.new_symlinks = vec![("c/link".to_owned(), "../top".to_owned())];
+
+        assert_eq!(differences.upload_folders_shallowest_first(), vec!["n", "a", "c", "a/b"]);
+    }
+
+    fn differences(old_files: &[&str], old_folders: &[&str], new_files: &[&str], new_folders: &[&str]) -> Differences {
+        let owned = |paths: &[&str]| paths.iter().map(|p| p.to_string()).collect::<Vec<String>>();
+        let mut differences = Differences::new();
+        differences.old_files = owned(old_files);
+        differences.old_folders = owned(old_folders);
+        differences.new_files = owned(new_files);
+        differences.new_folders = owned(new_folders);
+        differences
+    }
+
+    #[test]
+    fn overwritten_files_conflict_unless_overlay() {
+        let differences = differences(&["a/file", "b/removed"], &[], &["a/file"], &[]);
+        let conflicts = differences.conflict_index();
+
+        assert!(conflicts.conflicts_with_new("a/file", false));
+        assert!(!conflicts.conflicts_with_new("a/file", true));
+        assert!(!conflicts.conflicts_with_new("b/removed", false));
+    }
+
+    #[test]
+    fn replaced_dirs_conflict() {
+        // 目录a变成了文件，a和里面的所有内容都要先删除
+        let differences = differences(&["a/x", "a/sub/y"], &["a/sub", "a", "other"], &["a"], &[]);
+        let conflicts = differences.conflict_index();
+
+        for path in ["a", "a/x", "a/sub", "a/sub/y"] {
+            assert!(conflicts.conflicts_with_new(path, true), "{}", path);
+        }
+        assert!(!conflicts.conflicts_with_new("other", true));
+    }
+
+    #[test]
+    fn replaced_files_conflict() {
+        // 文件f变成了目录，文件g和h变成了包含新文件的目录
+        let differences = differences(&["f", "g", "ff", "h"], &[], &["g/child", "h/deep/er/file"], &["f"]);
+        let conflicts = differences.conflict_index();
+
+        assert!(conflicts.conflicts_with_new("f", true));
+        assert!(conflicts.conflicts_with_new("g", true));
+        assert!(conflicts.conflicts_with_new("h", true));
+        // 只是名字的前缀相同
+        assert!(!conflicts.conflicts_with_new("ff", true));
+    }
+
+    #[test]
+    fn symlinks_count_as_uploads() {
+        let mut differences = differences(&["link"], &["dir"], &[], &[]);
+        differences.new_symlinks = vec![("link".to_owned(), "target".to_owned()), ("dir".to_owned(), "target".to_owned())];
+        let conflicts = differences.conflict_index();
+
+        assert!(conflicts.conflicts_with_new("link", false));
+        assert!(!conflicts.conflicts_with_new("link", true));
+        assert!(conflicts.conflicts_with_new("dir", true));
+    }
+
+    fn file(name: &str, length: u64, sha1: &str) -> SimpleFile {
+        SimpleFile::new_file(name, length, sha1, 0)
+    }
+
+    fn with_mode(mut file: SimpleFile, mode: u32) -> SimpleFile {
+        file.as_file_mut().unwrap().mode = Some(mode);
+        file
+    }
+
+    #[test]
+    fn between_states() {
+        let old = DirData::new(vec![
+            file("same", 1, "aa"),
+            file("changed", 1, "aa"),
+            with_mode(file("chmod", 1, "aa"), 0o644),
+            file("removed", 1, "aa"),
+            SimpleFile::new_symlink("link", "a"),
+            SimpleFile::new_symlink("same-link", "a"),
+            SimpleFile::new_directory("dir", vec![file("inner", 1, "aa"), SimpleFile::new_directory("gone", vec![file("deep", 1, "aa")])]),
+            file("became-dir", 1, "aa"),
+            SimpleFile::new_directory("became-file", vec![file("child", 1, "aa")]),
+        ]);
+        let new = DirData::new(vec![
+            file("same", 1, "aa"),
+            file("changed", 2, "bb"),
+            with_mode(file("chmod", 1, "aa"), 0o755),
+            SimpleFile::new_symlink("link", "b"),
+            SimpleFile::new_symlink("same-link", "a"),
+            SimpleFile::new_directory("dir", vec![file("inner", 1, "aa"), file("added", 1, "aa")]),
+            SimpleFile::new_directory("became-dir", vec![SimpleFile::new_symlink("l", "x")]),
+            file("became-file", 1, "aa"),
+            SimpleFile::new_directory("new-dir", vec![file("f", 1, "aa")]),
+        ]);
+
+        let differences = Differences::between(&old, &new);
+        let sorted = |paths: &Vec<String>| {
+            let mut paths = paths.to_owned();
+            paths.sort();
+            paths
+        };
+
+        assert_eq!(sorted(&differences.old_files), vec!["became-dir", "became-file/child", "changed", "dir/gone/deep", "link", "removed"]);
+        assert_eq!(sorted(&differences.old_folders), vec!["became-file", "dir/gone"]);
+        assert_eq!(sorted(&differences.new_files), vec!["became-file", "changed", "dir/added", "new-dir/f"]);
+        assert_eq!(sorted(&differences.new_folders), vec!["became-dir", "new-dir"]);
+        assert_eq!(differences.new_symlinks.len(), 2);
+        assert!(differences.new_symlinks.contains(&("link".to_owned(), "b".to_owned())));
+        assert!(differences.new_symlinks.contains(&("became-dir/l".to_owned(), "x".to_owned())));
+        assert_eq!(differences.changed_metadata, vec!["chmod"]);
+        assert!(differences.has_differences());
+    }
+
+    #[test]
+    fn between_equal_states() {
+        let state = DirData::new(vec![file("a", 1, "aa"), SimpleFile::new_directory("d", vec![SimpleFile::new_symlink("l", "a")])]);
+        let differences = Differences::between(&state, &state.clone());
+
+        assert!(!differences.has_differences());
+        assert!(!differences.has_state_changes());
+    }
+}
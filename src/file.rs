@@ -190,6 +190,15 @@ impl File {
             .as_secs())
     }
 
+    /// 文件的修改时间(纳秒)
+    pub fn modified_nanos(&self) -> Result<u64> {
+        Ok(self.raw.metadata()?
+            .modified()?
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos() as u64)
+    }
+
     pub fn created(&self) -> Result<u64> {
         Ok(self.raw.metadata()?
             .created()?
@@ -209,6 +218,17 @@ impl File {
         fs::write(self.path(), contents)
     }
 
+    /// 先写到同一个目录下的临时文件里，再重命名覆盖掉目标文件，中途失败时不会留下写了一半的文件
+    pub fn write_atomically(&self, contents: impl AsRef<[u8]>) -> Result<()> {
+        let temp = self.parent()?.unwrap().append(&format!(".{}.tmp", self.name()))?;
+        if temp.exists() {
+            temp.rm()?;
+        }
+
+        fs::write(temp.path(), contents)?;
+        fs::rename(temp.path(), self.path())
+    }
+
     pub fn read(&self) -> Result<String> {
         if !self.exists() {
             return Err(Error::new(
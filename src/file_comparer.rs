@@ -1,35 +1,75 @@
 use crate::differences::Differences;
 use crate::file::File;
+use crate::file_metadata::MetadataTracking;
+use crate::file_selector::FileSelector;
 use crate::file_state::State;
 use crate::hash_cache::HashCache;
-use crate::rule_filter::RuleFilter;
 use crate::simple_file::FileData;
 use crate::simple_file::SimpleFile;
+use crate::source::Source;
+use crate::symlink_policy::LocalKind;
+use crate::symlink_policy::SymlinkPolicy;
+use crate::utils::get_dirname;
 
+use std::collections::HashSet;
 use std::io::Error;
+use std::io::ErrorKind;
 use std::io::Result;
 
+/// 状态里已经记录了、但是现在被过滤器排除掉的文件的处理方式
+pub enum ExcludedPolicy {
+    /// 保留在状态和远端里，之后不再更新
+    Keep,
+    /// 从远端删除，并从状态里移除
+    Delete,
+    /// 只从状态里移除，不删除远端的文件
+    Forget,
+}
+
+impl ExcludedPolicy {
+    pub fn parse(text: &str) -> Result<ExcludedPolicy> {
+        match text {
+            "keep" => Ok(ExcludedPolicy::Keep),
+            "delete" => Ok(ExcludedPolicy::Delete),
+            "forget" => Ok(ExcludedPolicy::Forget),
+            _ => Err(Error::new(ErrorKind::InvalidInput, format!("unknown value of on-filter-excluded: {}", text))),
+        }
+    }
+}
+
 pub struct FileComparer<'a> {
     pub base_path: File,
-    pub compare_func: Box<dyn Fn(&FileData, &File, &str, bool, &HashCache, bool) -> bool>,
+    pub prefix: String,
+    pub compare_func: Box<dyn Fn(&FileData, &File, &str, &HashCache, bool) -> bool>,
     pub hash_cache: &'a HashCache,
     pub debug_mode: bool,
-    pub fast_comparison: bool,
-    pub filters: &'a RuleFilter,
+    /// 是否更新内容没有变化的文件的修改时间
+    pub refresh_modified: bool,
+    pub filters: &'a FileSelector,
+    pub excluded_policy: &'a ExcludedPolicy,
+    pub symlinks: &'a SymlinkPolicy,
+    pub tracking: &'a MetadataTracking,
+    /// 是否移除没有任何内容的目录
+    pub prune_empty_dirs: bool,
     pub differences: Differences,
 }
 
 impl FileComparer<'_> {
-    pub fn new<'a, F>(base_path: &File, compare_func: F, hash_cache: &'a HashCache, fast_comparison: bool, filters: &'a RuleFilter, debug_mode: bool) -> FileComparer<'a>
-        where F : Fn(&FileData, &File, &str, bool, &HashCache, bool) -> bool + 'static
+    pub fn new<'a, F>(source: &'a Source, compare_func: F, refresh_modified: bool, excluded_policy: &'a ExcludedPolicy, symlinks: &'a SymlinkPolicy, tracking: &'a MetadataTracking, debug_mode: bool) -> FileComparer<'a>
+        where F : Fn(&FileData, &File, &str, &HashCache, bool) -> bool + 'static
     {
         FileComparer { 
-            base_path: base_path.clone(), 
+            base_path: source.dir.clone(), 
+            prefix: source.prefix.to_owned(),
             compare_func: Box::new(compare_func),
-            hash_cache,
+            hash_cache: &source.hash_cache,
             debug_mode,
-            fast_comparison,
-            filters,
+            refresh_modified,
+            filters: &source.selector,
+            excluded_policy,
+            symlinks,
+            tracking,
+            prune_empty_dirs: false,
             differences: Differences::new(),
         }
     }
@@ -42,44 +82,42 @@ impl FileComparer<'_> {
         let directory = directory.as_dir().unwrap();
         for t in contrast.files()? {
             let t = t?;
+            let kind = self.symlinks.classify(&t, &self.base_path)?;
 
-            if !directory.contains_file(t.name()) { // 文件不存在
-                let sf: Option<SimpleFile> = if t.is_dir() {
-                    Some(SimpleFile::from_real_directory(&t, Some((self.hash_cache, &self.base_path, self.debug_mode)))?)
-                } else if t.is_file() {
-                    Some(SimpleFile::from_real_file(&t, Some((self.hash_cache, &self.base_path, self.debug_mode)))?)
-                } else {
-                    None
-                };
+            match directory.get_file(t.name()) {
+                None => { // 文件不存在
+                    if let Some(sf) = self.scan(&t, &kind)? {
+                        self.add_new(&sf, &t)?;
+                    }
+                },
+                Some(corresponding) => { // 文件存在的话要进行进一步判断
+                    let same = match &kind {
+                        LocalKind::Dir => corresponding.is_dir(),
+                        LocalKind::File => corresponding.is_file() && 
+                            (self.compare_func)(corresponding.as_file().unwrap(), &t, &t.relativized_by(&self.base_path), self.hash_cache, self.debug_mode),
+                        LocalKind::Symlink(target) => corresponding.as_symlink().is_some_and(|l| &l.target == target),
+                        // 需要被忽略的文件会由find_old_files删除
+                        LocalKind::None => continue,
+                    };
 
-                if let Some(sf) = sf {
-                    self.add_new(&sf, &t)?;
-                }
-            } else { // 文件存在的话要进行进一步判断
-                let corresponding = directory.get_file(t.name())
-                    .ok_or_else(|| Error::new(std::io::ErrorKind::NotFound, "not found: ".to_string() + t.name()))?;
+                    if same {
+                        if corresponding.is_dir() {
+                            self.find_new_files(corresponding, &t)?;
+                        } else if let Some(recorded) = corresponding.as_file() {
+                            if self.refresh_modified {
+                                self.find_touched_file(recorded, &t)?;
+                            }
 
-                if t.is_dir() {
-                    if corresponding.is_file() {
-                        // 先删除旧的再获取新的
-                        self.add_old(corresponding, &contrast.relativized_by(&self.base_path))?;
-                        self.add_new(corresponding, &t)?;
-                    } else if corresponding.is_dir() {
-                        self.find_new_files(&corresponding, &t)?;
-                    }
-                } else {
-                    if corresponding.is_file() {
-                        if !(self.compare_func)(&corresponding.as_file().unwrap(), &t, &t.relativized_by(&self.base_path), self.fast_comparison, self.hash_cache, self.debug_mode) {
-                            // 先删除旧的再获取新的
-                            self.add_old(corresponding, &contrast.relativized_by(&self.base_path))?;
-                            self.add_new(corresponding, &t)?;
+                            if self.tracking.is_enabled() {
+                                self.find_changed_metadata(recorded, &t)?;
+                            }
                         }
-                    } else {
+                    } else if let Some(sf) = self.scan(&t, &kind)? {
                         // 先删除旧的再获取新的
-                        self.add_old(&corresponding, &contrast.relativized_by(&self.base_path))?;
-                        self.add_new(&corresponding, &t)?;
+                        self.add_old(corresponding, &contrast.relativized_by(&self.base_path))?;
+                        self.add_new(&sf, &t)?;
                     }
-                }
+                },
             }
         }
 
@@ -94,56 +132,111 @@ impl FileComparer<'_> {
         for f in &directory.as_dir().unwrap().files {
             let corresponding = contrast.append(&f.name)?;
 
-            if corresponding.exists() {
+            match self.symlinks.classify(&corresponding, &self.base_path)? {
                 // 如果两边都是目录，递归并进一步判断
-                if f.is_dir() && corresponding.is_dir() {
+                LocalKind::Dir => if f.is_dir() {
                     self.find_old_files(&f, &corresponding)?;
-                }
-                // 其它情况均由findMissingFiles进行处理了，这里不需要重复计算
-            } else { // 如果远程端没有有这个文件，就直接删掉好了
-                self.add_old(&f, &contrast.relativized_by(&self.base_path))?;
+                },
+                // 如果远程端没有有这个文件(或者需要被忽略)，就直接删掉好了
+                LocalKind::None => self.add_old(&f, &contrast.relativized_by(&self.base_path))?,
+                // 其它情况均由find_new_files进行处理了，这里不需要重复计算
+                _ => {},
             }
         }
         Ok(())
     }
 
+    /// 内容相同、但是修改时间和记录的不一样的文件(包括旧版本的状态文件里只记录了秒的文件)，只需要更新状态
+    fn find_touched_file(&mut self, recorded: &FileData, file: &File) -> Result<()> {
+        let path = file.relativized_by(&self.base_path);
+
+        // 过滤文件
+        if file.modified_nanos().ok() != recorded.modified_ns && self.filter(&path, false)? {
+            self.differences.touched_files.push(self.with_prefix(&path));
+        }
+
+        Ok(())
+    }
+
+    /// 内容相同的文件只对比权限和所有者
+    fn find_changed_metadata(&mut self, recorded: &FileData, file: &File) -> Result<()> {
+        let path = file.relativized_by(&self.base_path);
+
+        // 过滤文件
+        if !self.filter(&path, false)? {
+            return Ok(());
+        }
+
+        let path = self.with_prefix(&path);
+        if self.tracking.changed(recorded, file) {
+            self.differences.changed_metadata.push(path);
+        } else if self.tracking.unrecorded(recorded, file) && self.differences.touched_files.last() != Some(&path) {
+            // 状态里还没有记录的元数据不需要执行set-mode，和修改时间一起更新到状态里就行
+            self.differences.touched_files.push(path);
+        }
+
+        Ok(())
+    }
+
+    /// 按照符号链接策略扫描一个本地的文件/目录，需要被忽略时返回None
+    fn scan(&self, file: &File, kind: &LocalKind) -> Result<Option<SimpleFile>> {
+        Ok(match kind {
+            LocalKind::Dir => {
+                let mut files = Vec::<SimpleFile>::new();
+                for f in file.files()? {
+                    let f = f?;
+                    if let Some(sf) = self.scan(&f, &self.symlinks.classify(&f, &self.base_path)?)? {
+                        files.push(sf);
+                    }
+                }
+                Some(SimpleFile::new_directory(file.name(), files))
+            },
+            LocalKind::File => SimpleFile::from_real_file(file, Some((self.hash_cache, &self.base_path, self.debug_mode))).ok(),
+            LocalKind::Symlink(target) => Some(SimpleFile::new_symlink(file.name(), target)),
+            LocalKind::None => None,
+        })
+    }
+
     /// 添加需要传输的文件
     /// 
     /// missing: 缺失的文件对象(文件/目录)<br/>
     /// template: 对照模板(文件/目录)
     fn add_new<'a>(&mut self, missing: &SimpleFile, contrast: &File) -> Result<()> {
-        if missing.is_dir() != contrast.is_dir() {
+        if missing.is_dir() && !contrast.is_dir() {
             return Err(Error::new(std::io::ErrorKind::InvalidData, "ambiguous file type"));
         }
 
         if let Some(missing) = missing.as_dir() {
             let folder = contrast.relativized_by(&self.base_path).to_string();
+            let uploads = self.differences.new_files.len() + self.differences.new_symlinks.len();
+            let contents = uploads + self.differences.new_folders.len();
 
-            if !self.differences.new_folders.contains(&folder) && folder != "." {
-                // 过滤文件
-                if self.filter(&folder) {
-                    self.differences.new_folders.push(folder);
-                }
+            for m in &missing.files {
+                self.add_new(&m, &contrast.append(&m.name)?)?;
             }
 
-            for m in &missing.files {
-                let corresponding = contrast.append(&m.name)?;
+            // 开启了prune-empty-dirs时，里面没有任何需要上传的文件的目录不会被创建
+            let pruned = self.prune_empty_dirs && self.differences.new_files.len() + self.differences.new_symlinks.len() == uploads;
+            // 里面有需要上传的内容时，目录不管有没有被过滤掉都需要创建
+            let occupied = self.differences.new_files.len() + self.differences.new_symlinks.len() + self.differences.new_folders.len() > contents;
 
-                if m.is_dir() {
-                    self.add_new(&m, &corresponding)?;
-                } else {
-                    let path = corresponding.relativized_by(&self.base_path);
-                    // 过滤文件
-                    if self.filter(&path) {
-                        self.differences.new_files.push(path.to_string())
-                    }
+            if !self.differences.new_folders.contains(&self.with_prefix(&folder)) && folder != "." && !folder.is_empty() {
+                // 过滤文件
+                if occupied || (!pruned && self.filter(&folder, true)?) {
+                    self.differences.new_folders.push(self.with_prefix(&folder));
                 }
             }
+        } else if let Some(link) = missing.as_symlink() {
+            let path = contrast.relativized_by(&self.base_path);
+            // 过滤文件
+            if self.filter(&path, false)? {
+                self.differences.new_symlinks.push((self.with_prefix(&path), link.target.to_owned()))
+            }
         } else if let Some(_missing) = missing.as_file() {
             let path = contrast.relativized_by(&self.base_path);
             // 过滤文件
-            if self.filter(&path) {
-                self.differences.new_files.push(path)
+            if self.filter(&path, false)? {
+                self.differences.new_files.push(self.with_prefix(&path))
             }
         }
 
@@ -162,38 +255,204 @@ impl FileComparer<'_> {
             for u in &existing.files {
                 if u.is_dir() {
                     self.add_old(&u, path)?;
-                } else if u.is_file() {
+                } else {
                     let path = path.to_string() + "/" + &u.name;
                     let path = if path.starts_with("./") { &path[2..] } else { &path[..] };
 
                     // 过滤文件
-                    if self.filter(&path) {
-                        self.differences.old_files.push(path.to_string());
+                    if self.filter(&path, false)? {
+                        self.differences.old_files.push(self.with_prefix(path));
                     }
                 }
             }
 
             // 过滤文件
-            if self.filter(&path) {
-                self.differences.old_folders.push(path.to_string());
+            if self.filter(&path, true)? {
+                self.differences.old_folders.push(self.with_prefix(path));
             }
-        } else if let Some(_existing) = existing.as_file() {
-            // 过滤文件
-            if self.filter(&path) {
-                self.differences.old_files.push(path.to_string());
+        } else {
+            // 过滤文件(符号链接也和文件一样删除)
+            if self.filter(&path, false)? {
+                self.differences.old_files.push(self.with_prefix(path));
             }
         }
 
         Ok(())
     }
 
-    fn filter<'a>(&self, test: &str) -> bool {
-        self.filters.test_all(test, true)
+    /// 找出状态里已经记录了、但是现在被过滤器排除掉的文件，并按照excluded_policy进行处理
+    /// 
+    /// files: 状态里某个目录下的文件<br/>
+    /// directory: 这个目录在base_path里的相对路径<br/>
+    /// removed: 已经要被删除的文件和目录<br/>
+    /// 返回这个目录下的文件是否全部都会被移除
+    fn find_excluded_files(&mut self, files: &[SimpleFile], directory: &str, removed: &HashSet<String>) -> Result<bool> {
+        let mut all_removed = true;
+
+        for f in files {
+            let path = if directory.is_empty() { f.name.to_owned() } else { directory.to_owned() + "/" + &f.name };
+            let state_path = self.with_prefix(&path);
+
+            if removed.contains(&state_path) {
+                continue;
+            }
+
+            // 目录只有在里面的文件全部被移除之后才能被移除
+            // 被@min-age等时间条件暂时排除的文件不算在内
+            let excluded = if let Some(dir) = f.as_dir() {
+                self.find_excluded_files(&dir.files, &path, removed)? && self.filters.is_excluded(&path, true)?
+            } else {
+                self.filters.is_excluded(&path, false)?
+            };
+
+            if !excluded {
+                all_removed = false;
+                continue;
+            }
+
+            if self.debug_mode {
+                println!("filter excluded: {}", state_path);
+            }
+
+            match self.excluded_policy {
+                ExcludedPolicy::Keep => all_removed = false,
+                ExcludedPolicy::Delete => if f.is_dir() {
+                    self.differences.old_folders.push(state_path.to_owned());
+                } else {
+                    self.differences.old_files.push(state_path.to_owned());
+                },
+                ExcludedPolicy::Forget => self.differences.forgotten.push(state_path.to_owned()),
+            }
+
+            if !f.is_dir() {
+                self.differences.excluded.push(state_path);
+            }
+        }
+
+        Ok(all_removed)
+    }
+
+    /// 找出状态里的内容全部都会被删除的目录，并把这些目录也删除掉
+    /// 
+    /// files: 状态里某个目录下的文件<br/>
+    /// directory: 这个目录在base_path里的相对路径<br/>
+    /// removed: 已经要被删除的文件和目录<br/>
+    /// occupied: 有新内容要上传的目录<br/>
+    /// 返回这个目录在处理完成后是否会变成空目录
+    fn find_empty_dirs(&mut self, files: &[SimpleFile], directory: &str, removed: &HashSet<String>, occupied: &HashSet<String>) -> Result<bool> {
+        let mut all_removed = true;
+
+        for f in files {
+            let path = if directory.is_empty() { f.name.to_owned() } else { directory.to_owned() + "/" + &f.name };
+            let state_path = self.with_prefix(&path);
+
+            if removed.contains(&state_path) {
+                continue;
+            }
+
+            let prunable = match f.as_dir() {
+                Some(dir) => self.find_empty_dirs(&dir.files, &path, removed, occupied)? && 
+                    !occupied.contains(&state_path) && self.filter(&path, true)?,
+                None => false,
+            };
+
+            if !prunable {
+                all_removed = false;
+                continue;
+            }
+
+            if self.debug_mode {
+                println!("prune empty dir: {}", state_path);
+            }
+
+            self.differences.old_folders.push(state_path);
+        }
+
+        Ok(all_removed)
+    }
+
+    fn filter<'a>(&self, test: &str, is_dir: bool) -> Result<bool> {
+        self.filters.is_selected(test, is_dir)
+    }
+
+    /// 将base_path里的相对路径转换为状态里的路径(加上前缀)
+    fn with_prefix(&self, path: &str) -> String {
+        if self.prefix.is_empty() {
+            path.to_owned()
+        } else if path.is_empty() {
+            self.prefix.to_owned()
+        } else {
+            self.prefix.to_owned() + "/" + path
+        }
+    }
+
+    /// 对比整个base_path
+    /// 
+    /// contrast: 用来对照的状态，base_path对应的是状态里prefix所指的目录
+    pub fn compare(&mut self, contrast: &State) -> Result<()> {
+        self.compare_subtree("", contrast)
     }
 
-    pub fn compare(&mut self, directory: &File, contrast: &State) -> Result<()> {
-        self.find_new_files(&SimpleFile::new_directory("no_name", contrast.clone().files.files), directory)?;
-        self.find_old_files(&SimpleFile::new_directory("no_name", contrast.clone().files.files), directory)?;
+    /// 只对比base_path下的某一个子目录
+    /// 
+    /// relative_dir: 子目录的相对路径，需要在本地和contrast中都是目录，为空时对比整个base_path<br/>
+    /// contrast: 用来对照的状态，base_path对应的是状态里prefix所指的目录
+    pub fn compare_subtree(&mut self, relative_dir: &str, contrast: &State) -> Result<()> {
+        let directory = if relative_dir.is_empty() { self.base_path.clone() } else { self.base_path.append(relative_dir)? };
+        let state_path = self.with_prefix(relative_dir);
+
+        let files = if state_path.is_empty() {
+            contrast.files.files.clone()
+        } else if let Some(dir) = contrast.files.get_file(&state_path) {
+            dir.as_dir()
+                .ok_or_else(|| Error::new(std::io::ErrorKind::InvalidData, "not a directory in the state: ".to_string() + &state_path))?
+                .files.clone()
+        } else if relative_dir.is_empty() {
+            // 状态里还没有前缀对应的目录，需要先把前缀的每一级目录都创建出来
+            let mut current = "".to_string();
+            for name in self.prefix.split("/") {
+                current = if current.is_empty() { name.to_owned() } else { current + "/" + name };
+                if !contrast.files.contains_file(&current) && !self.differences.new_folders.contains(&current) {
+                    self.differences.new_folders.push(current.to_owned());
+                }
+            }
+            Vec::new()
+        } else {
+            return Err(Error::new(std::io::ErrorKind::NotFound, "not a directory in the state: ".to_string() + &state_path));
+        };
+
+        self.find_new_files(&SimpleFile::new_directory("no_name", files.clone()), &directory)?;
+        self.find_old_files(&SimpleFile::new_directory("no_name", files.clone()), &directory)?;
+
+        let removed = self.differences.old_files.iter()
+            .chain(self.differences.old_folders.iter())
+            .map(|e| e.to_owned())
+            .collect::<HashSet<String>>();
+        self.find_excluded_files(&files, relative_dir, &removed)?;
+
+        if self.prune_empty_dirs {
+            let removed = self.differences.old_files.iter()
+                .chain(self.differences.old_folders.iter())
+                .map(|e| e.to_owned())
+                .collect::<HashSet<String>>();
+
+            // 新上传的内容所在的每一级目录都不能被删除
+            let mut occupied = HashSet::<String>::new();
+            let added = self.differences.new_files.iter()
+                .chain(self.differences.new_symlinks.iter().map(|l| &l.0))
+                .chain(self.differences.new_folders.iter());
+            for path in added {
+                let mut current = get_dirname(path);
+                while let Some(dir) = current {
+                    if !occupied.insert(dir.to_owned()) {
+                        break;
+                    }
+                    current = get_dirname(dir);
+                }
+            }
+
+            self.find_empty_dirs(&files, relative_dir, &removed, &occupied)?;
+        }
 
         Ok(())
     }
@@ -0,0 +1,82 @@
+use crate::file::File;
+use crate::simple_file::FileData;
+
+/// 文件的权限和所有者，只在unix平台上可用
+pub struct FileMetadata {
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+/// 需要记录到状态里的文件元数据
+pub struct MetadataTracking {
+    /// 记录文件的权限(比如可执行位)
+    pub mode: bool,
+    /// 记录文件的所有者和所属组
+    pub owner: bool,
+}
+
+impl MetadataTracking {
+    pub fn new(mode: bool, owner: bool) -> MetadataTracking {
+        MetadataTracking { mode, owner }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.mode || self.owner
+    }
+
+    /// 读取本地文件中需要记录的元数据
+    pub fn read(&self, file: &File) -> FileMetadata {
+        let metadata = FileMetadata::read(file);
+
+        FileMetadata {
+            mode: if self.mode { metadata.mode } else { None },
+            uid: if self.owner { metadata.uid } else { None },
+            gid: if self.owner { metadata.gid } else { None },
+        }
+    }
+
+    /// 判断本地文件的元数据是否和状态里记录的不一样，无法获取的和状态里还没有记录的元数据不参与对比
+    pub fn changed(&self, recorded: &FileData, file: &File) -> bool {
+        let metadata = self.read(file);
+        let differs = |local: Option<u32>, recorded: Option<u32>| local.is_some() && recorded.is_some() && local != recorded;
+
+        differs(metadata.mode, recorded.mode) || differs(metadata.uid, recorded.uid) || differs(metadata.gid, recorded.gid)
+    }
+
+    /// 判断状态里是否还没有记录本地文件的某些元数据(比如刚刚开启track-mode的时候)，这些元数据只需要直接记录到状态里
+    pub fn unrecorded(&self, recorded: &FileData, file: &File) -> bool {
+        let metadata = self.read(file);
+
+        (metadata.mode.is_some() && recorded.mode.is_none()) ||
+            (metadata.uid.is_some() && recorded.uid.is_none()) ||
+            (metadata.gid.is_some() && recorded.gid.is_none())
+    }
+}
+
+impl FileMetadata {
+    #[cfg(unix)]
+    pub fn read(file: &File) -> FileMetadata {
+        use std::os::unix::fs::MetadataExt;
+
+        match file.get_raw().metadata() {
+            Ok(metadata) => FileMetadata {
+                mode: Some(metadata.mode() & 0o7777),
+                uid: Some(metadata.uid()),
+                gid: Some(metadata.gid()),
+            },
+            Err(_) => FileMetadata { mode: None, uid: None, gid: None },
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub fn read(_file: &File) -> FileMetadata {
+        FileMetadata { mode: None, uid: None, gid: None }
+    }
+}
+
+impl Clone for MetadataTracking {
+    fn clone(&self) -> Self {
+        Self { mode: self.mode, owner: self.owner }
+    }
+}
\ No newline at end of file
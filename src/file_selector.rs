@@ -0,0 +1,103 @@
+use std::io::Result;
+
+use crate::AppResult;
+use crate::app_config::SourceConfig;
+use crate::file::File;
+use crate::ignore_filter::IgnoreFilter;
+use crate::rule_filter::RuleFilter;
+
+/// 决定源目录里的一个文件是否需要同步
+///
+/// 文件需要匹配任意一条include(include为空时视为全部匹配)，并且不匹配任何一条exclude，
+/// 同时还要满足file-filters和ignore-patterns。以@开头的文件属性条件会对源目录里对应的文件进行判断，
+/// 文件已经不存在(被删除的文件)或者条件不适用于这种文件时，这个条件会被跳过
+///
+/// 目录用加上了结尾的/的路径来匹配include，并且include里的文件属性条件对目录不生效(比如^assets/会选中assets目录)。
+/// 里面有需要同步的文件时，目录不管有没有被选中都会被创建
+pub struct FileSelector {
+    pub dir: File,
+    pub file_filter: RuleFilter,
+    pub include: RuleFilter,
+    pub exclude: RuleFilter,
+    pub ignore_filter: IgnoreFilter,
+    /// 去掉了和时间有关的条件的过滤规则，用于判断文件是否被长期排除
+    stable_file_filter: RuleFilter,
+    stable_include: RuleFilter,
+    stable_exclude: RuleFilter,
+}
+
+impl FileSelector {
+    pub fn new(dir: &File, config: &SourceConfig) -> AppResult<FileSelector> {
+        Ok(FileSelector {
+            dir: dir.clone(),
+            file_filter: RuleFilter::new(&config.file_filters)?,
+            include: RuleFilter::new(&config.include)?,
+            exclude: RuleFilter::new(&config.exclude)?,
+            ignore_filter: IgnoreFilter::new(dir, &config.ignore_patterns)?,
+            stable_file_filter: RuleFilter::new(&config.file_filters)?.without_transient_rules(),
+            stable_include: RuleFilter::new(&config.include)?.without_transient_rules(),
+            stable_exclude: RuleFilter::new(&config.exclude)?.without_transient_rules(),
+        })
+    }
+
+    /// 判断源目录里的一个相对路径是否需要同步
+    pub fn is_selected(&self, relative_path: &str, is_dir: bool) -> Result<bool> {
+        self.select(relative_path, is_dir, &self.include, &self.exclude, &self.file_filter)
+    }
+
+    /// 判断一个文件是否被长期排除掉了，和时间有关的条件(@min-age、@max-age)只是暂时排除文件，不算在内
+    pub fn is_excluded(&self, relative_path: &str, is_dir: bool) -> Result<bool> {
+        Ok(!self.select(relative_path, is_dir, &self.stable_include, &self.stable_exclude, &self.stable_file_filter)?)
+    }
+
+    fn select(&self, relative_path: &str, is_dir: bool, include: &RuleFilter, exclude: &RuleFilter, file_filter: &RuleFilter) -> Result<bool> {
+        let file = self.dir.append(relative_path).ok();
+        let file = file.as_ref();
+
+        Ok(!exclude.test_any(relative_path, file, false) &&
+            FileSelector::is_included(include, relative_path, file, is_dir) &&
+            file_filter.test_all(relative_path, file, true) &&
+            !self.ignore_filter.is_ignored(relative_path, is_dir)?)
+    }
+
+    /// 判断源目录里的一个相对路径是否需要同步，并返回做出决定的规则
+    pub fn explain(&self, relative_path: &str, is_dir: bool) -> Result<(bool, String)> {
+        let file = self.dir.append(relative_path).ok();
+        let file = file.as_ref();
+
+        if let Some(rule) = self.exclude.find_matched(relative_path, file) {
+            return Ok((false, format!("exclude {}", rule)));
+        }
+
+        if !FileSelector::is_included(&self.include, relative_path, file, is_dir) {
+            return Ok((false, "no include matched".to_owned()));
+        }
+
+        if let Some(rule) = self.file_filter.find_unmatched(relative_path, file) {
+            return Ok((false, format!("file-filters {}", rule)));
+        }
+
+        if let Some(rule) = self.ignore_filter.explain(relative_path, is_dir)? {
+            return Ok((false, format!("ignore-patterns {}", rule)));
+        }
+
+        let matched = if is_dir {
+            self.include.find_matched(&(relative_path.to_owned() + "/"), None)
+        } else {
+            self.include.find_matched(relative_path, file)
+        };
+
+        Ok(match matched {
+            Some(rule) => (true, format!("include {}", rule)),
+            None => (true, "no rule excluded it".to_owned()),
+        })
+    }
+
+    fn is_included(include: &RuleFilter, relative_path: &str, file: Option<&File>, is_dir: bool) -> bool {
+        if is_dir {
+            include.test_any(&(relative_path.to_owned() + "/"), None, true)
+        } else {
+            include.test_any(relative_path, file, true)
+        }
+    }
+}
\ No newline at end of file
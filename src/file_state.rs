@@ -1,10 +1,17 @@
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Result;
+
 use json::JsonValue;
 use json::object;
 
+use crate::differences::Differences;
 use crate::file::File;
-use crate::hash_cache::HashCache;
+use crate::file_metadata::MetadataTracking;
 use crate::simple_file::DirData;
+use crate::simple_file::FileData;
 use crate::simple_file::SimpleFile;
+use crate::source::Source;
 use crate::utils::get_basename;
 use crate::utils::get_dirname;
 
@@ -22,6 +29,8 @@ impl State {
                     if f.has_key("children") { 
                         let children = gen(&f["children"]);
                         files.push(SimpleFile::new_directory(name, children));
+                    } else if let Some(target) = f["link"].as_str() {
+                        files.push(SimpleFile::new_symlink(name, target));
                     } else {
                         let length = f["length"].as_u64();
                         let hash = f["hash"].as_str();
@@ -30,7 +39,13 @@ impl State {
                             let length = length.unwrap();
                             let hash = hash.unwrap();
                             let modified = modified.unwrap();
-                            files.push(SimpleFile::new_file(name, length, hash, modified));
+                            let mut file = SimpleFile::new_file(name, length, hash, modified);
+                            let data = file.as_file_mut().unwrap();
+                            data.modified_ns = f["modified-ns"].as_u64();
+                            data.mode = f["mode"].as_u32();
+                            data.uid = f["uid"].as_u32();
+                            data.gid = f["gid"].as_u32();
+                            files.push(file);
                         }
                     }
                 }
@@ -47,17 +62,36 @@ impl State {
             for f in &dir.files {
                 let fname = f.name.to_owned();
                 if let Some(f) = f.as_file() {
-                    array.push(object! {
+                    let mut file = object! {
                         name: fname,
                         length: f.length,
                         hash: f.sha1.to_owned(),
                         modified: f.modified,
-                    }).unwrap();
+                    };
+                    if let Some(modified_ns) = f.modified_ns {
+                        file["modified-ns"] = modified_ns.into();
+                    }
+                    // 只有开启了track-mode/track-owner时才会记录权限
+                    if let Some(mode) = f.mode {
+                        file["mode"] = mode.into();
+                    }
+                    if let Some(uid) = f.uid {
+                        file["uid"] = uid.into();
+                    }
+                    if let Some(gid) = f.gid {
+                        file["gid"] = gid.into();
+                    }
+                    array.push(file).unwrap();
                 } else if let Some(f) = f.as_dir() {
                     array.push(object! {
                         name: fname,
                         children: gen(&f)
                     }).unwrap();
+                } else if let Some(f) = f.as_symlink() {
+                    array.push(object! {
+                        name: fname,
+                        link: f.target.to_owned(),
+                    }).unwrap();
                 }
             }
 
@@ -71,34 +105,166 @@ impl State {
         self.files.remove_file(path);
     }
 
-    pub fn make_dir(&mut self, path: &str) {
-        let parent = get_dirname(path);
-        let filename = get_basename(path);
-
-        let dir: &mut DirData = if parent.is_some() {
-            self.files.get_file_mut(parent.unwrap()).unwrap().as_dir_mut().unwrap()
-        } else {
-            &mut self.files
+    /// 找到一个路径在状态里的上级目录，上级目录不存在时返回错误
+    fn parent_dir_mut(&mut self, path: &str) -> Result<&mut DirData> {
+        let parent = match get_dirname(path) {
+            Some(parent) => parent,
+            None => return Ok(&mut self.files),
         };
+
+        self.files.get_file_mut(parent)
+            .and_then(|f| f.as_dir_mut())
+            .ok_or_else(|| Error::new(ErrorKind::NotFound, format!("the parent directory is not in the state: {}", path)))
+    }
+
+    pub fn make_dir(&mut self, path: &str) -> Result<()> {
+        let filename = get_basename(path);
+        let dir = self.parent_dir_mut(path)?;
         
-        dir.files.push(SimpleFile::new_directory(filename, Vec::new()));
+        // 已经存在的目录保持不变，其它类型的文件会被替换成目录
+        if !dir.files.iter().any(|f| f.name == filename && f.is_dir()) {
+            State::put(dir, SimpleFile::new_directory(filename, Vec::new()));
+        }
+
+        Ok(())
     }
 
-    pub fn add_file(&mut self, path: &str, sourcedir: &File, hash_cache: &HashCache, debug_mode: bool) {
-        let parent = get_dirname(path);
+    /// 创建一个路径在状态里的每一级上级目录
+    pub fn make_parent_dirs(&mut self, path: &str) -> Result<()> {
+        let mut parents = Vec::<&str>::new();
+        let mut current = get_dirname(path);
+        while let Some(dir) = current {
+            parents.push(dir);
+            current = get_dirname(dir);
+        }
+
+        for dir in parents.iter().rev() {
+            self.make_dir(dir)?;
+        }
+
+        Ok(())
+    }
+
+    /// 将一个文件放到目录里，同名的文件会被替换掉
+    fn put(dir: &mut DirData, file: SimpleFile) {
+        match dir.files.iter().position(|f| f.name == file.name) {
+            Some(index) => dir.files[index] = file,
+            None => dir.files.push(file),
+        }
+    }
+
+    /// 将一个文件添加到状态里，已经记录了的同名文件会被替换掉
+    /// 
+    /// path: 文件在状态里的路径<br/>
+    /// source: 文件所在的源目录<br/>
+    /// tracking: 需要记录的文件元数据
+    pub fn add_file(&mut self, path: &str, source: &Source, tracking: &MetadataTracking, debug_mode: bool) -> Result<()> {
         let filename = get_basename(path);
+        let dir = self.parent_dir_mut(path)?;
 
-        let dir: &mut DirData = if parent.is_some() {
-            self.files.get_file_mut(parent.unwrap()).unwrap().as_dir_mut().unwrap()
-        } else {
-            &mut self.files
-        };
+        let local_path = source.to_local_path(path)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, format!("the path does not belong to the source directory: {}", path)))?;
+        let file = source.dir.append(&local_path)?;
+        let length = file.length()?;
+        let sha1 = source.hash_cache.get_hash(&local_path, debug_mode);
+        let modified = file.modified()?;
+        let mut simple_file = SimpleFile::new_file(filename, length, &sha1, modified);
+        simple_file.as_file_mut().unwrap().modified_ns = file.modified_nanos().ok();
+        State::apply_metadata(simple_file.as_file_mut().unwrap(), &file, tracking);
+        State::put(dir, simple_file);
+
+        Ok(())
+    }
+
+    /// 更新状态里一个文件的元数据(权限和所有者)
+    /// 
+    /// path: 文件在状态里的路径<br/>
+    /// source: 文件所在的源目录<br/>
+    /// tracking: 需要记录的文件元数据
+    pub fn update_metadata(&mut self, path: &str, source: &Source, tracking: &MetadataTracking) {
+        let local_path = source.to_local_path(path).unwrap();
+        let file = source.dir.append(&local_path).unwrap();
+
+        if let Some(data) = self.files.get_file_mut(path).and_then(|f| f.as_file_mut()) {
+            State::apply_metadata(data, &file, tracking);
+        }
+    }
+
+    /// 内容没有变化的文件只更新状态里记录的修改时间，并补上状态里还没有记录的元数据
+    /// 
+    /// path: 文件在状态里的路径<br/>
+    /// source: 文件所在的源目录<br/>
+    /// tracking: 需要记录的文件元数据，已经记录了的元数据不会被修改(它们的变化由set-mode处理)
+    pub fn refresh_modified(&mut self, path: &str, source: &Source, tracking: &MetadataTracking) {
+        let local_path = source.to_local_path(path).unwrap();
+        let file = source.dir.append(&local_path).unwrap();
+
+        if let Some(data) = self.files.get_file_mut(path).and_then(|f| f.as_file_mut()) {
+            data.modified = file.modified().unwrap_or(data.modified);
+            data.modified_ns = file.modified_nanos().ok();
 
-        let file = sourcedir.append(path).unwrap();
-        let length = file.length().unwrap();
-        let sha1 = hash_cache.get_hash(path, debug_mode);
-        let modified = file.modified().unwrap();
-        dir.files.push(SimpleFile::new_file(filename, length, &sha1, modified));
+            let metadata = tracking.read(&file);
+            data.mode = data.mode.or(metadata.mode);
+            data.uid = data.uid.or(metadata.uid);
+            data.gid = data.gid.or(metadata.gid);
+        }
+    }
+
+    fn apply_metadata(data: &mut FileData, file: &File, tracking: &MetadataTracking) {
+        let metadata = tracking.read(file);
+        data.mode = metadata.mode;
+        data.uid = metadata.uid;
+        data.gid = metadata.gid;
+    }
+
+    /// 将一个符号链接添加到状态里
+    /// 
+    /// path: 符号链接在状态里的路径<br/>
+    /// target: 链接的目标
+    pub fn add_symlink(&mut self, path: &str, target: &str) -> Result<()> {
+        let filename = get_basename(path);
+        let dir = self.parent_dir_mut(path)?;
+
+        State::put(dir, SimpleFile::new_symlink(filename, target));
+
+        Ok(())
+    }
+
+    /// 把从base到ours所做的修改重新应用到这个状态上，用于合并两个基于同一个状态分别做出的修改，双方都修改了的文件以ours为准
+    ///
+    /// 只有修改时间变化了的文件不会被合并，下一次对比的时候会被重新记录
+    pub fn rebase(&mut self, base: &State, ours: &State) -> Result<()> {
+        let differences = Differences::between(&base.files, &ours.files);
+
+        for path in differences.old_files.iter().chain(differences.old_folders_deepest_first()) {
+            if self.files.contains_file(path) {
+                self.files.remove_file(path);
+            }
+        }
+
+        let added = differences.new_folders_shallowest_first().into_iter()
+            .chain(differences.new_files.iter())
+            .chain(differences.new_symlinks.iter().map(|l| &l.0))
+            .chain(differences.changed_metadata.iter());
+
+        for path in added {
+            let entry = match ours.files.get_file(path) {
+                Some(entry) if entry.is_dir() => SimpleFile::new_directory(&entry.name, Vec::new()),
+                Some(entry) => entry.clone(),
+                None => continue,
+            };
+
+            // 上级目录可能已经被对方删掉了
+            self.make_parent_dirs(path)?;
+
+            if entry.is_dir() {
+                self.make_dir(path)?;
+            } else {
+                State::put(self.parent_dir_mut(path)?, entry);
+            }
+        }
+
+        Ok(())
     }
 }
 
@@ -107,3 +273,79 @@ impl Clone for State {
         Self { files: self.files.clone() }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_parent_is_an_error() {
+        let mut state = State { files: DirData::new(Vec::new()) };
+
+        assert_eq!(state.make_dir("a/b").err().unwrap().kind(), ErrorKind::NotFound);
+        assert_eq!(state.add_symlink("a/link", "x").err().unwrap().kind(), ErrorKind::NotFound);
+
+        state.make_dir("a").unwrap();
+        state.make_dir("a/b").unwrap();
+        state.add_symlink("a/link", "x").unwrap();
+        assert!(state.files.get_file("a/b").unwrap().is_dir());
+        assert_eq!(state.files.get_file("a/link").unwrap().as_symlink().unwrap().target, "x");
+
+        // 上级目录是一个文件
+        assert!(state.make_dir("a/link/c").is_err());
+    }
+
+    #[test]
+    fn make_parent_dirs() {
+        let mut state = State { files: DirData::new(Vec::new()) };
+        state.make_dir("a").unwrap();
+        state.add_symlink("a/keep", "x").unwrap();
+
+        state.make_parent_dirs("a/b/c/file").unwrap();
+        assert!(state.files.get_file("a/b/c").unwrap().is_dir());
+        assert!(state.files.get_file("a/b/c/file").is_none());
+        // 已经存在的目录保持不变
+        assert!(state.files.get_file("a/keep").is_some());
+    }
+
+    #[test]
+    fn rebase_applies_our_changes() {
+        let file = |name: &str, sha1: &str| SimpleFile::new_file(name, 1, sha1, 0);
+        let base = State { files: DirData::new(vec![
+            file("kept", "aa"),
+            file("ours-removed", "aa"),
+            file("both-changed", "aa"),
+            SimpleFile::new_directory("dir", vec![file("inner", "aa")]),
+            SimpleFile::new_directory("theirs-removed", vec![file("x", "aa")]),
+        ]) };
+
+        // 别人在base的基础上做的修改
+        let mut theirs = base.clone();
+        theirs.files.get_file_mut("both-changed").unwrap().as_file_mut().unwrap().sha1 = "bb".to_owned();
+        theirs.files.get_file_mut("kept").unwrap().as_file_mut().unwrap().sha1 = "bb".to_owned();
+        theirs.remove_file_or_dir("theirs-removed");
+        theirs.files.files.push(file("theirs-added", "bb"));
+
+        // 自己在base的基础上做的修改
+        let mut ours = base.clone();
+        ours.remove_file_or_dir("ours-removed");
+        ours.files.get_file_mut("both-changed").unwrap().as_file_mut().unwrap().sha1 = "cc".to_owned();
+        ours.make_dir("dir/sub").unwrap();
+        ours.add_symlink("dir/sub/link", "../inner").unwrap();
+        ours.make_dir("theirs-removed/sub").unwrap();
+        ours.add_symlink("theirs-removed/sub/link", "x").unwrap();
+
+        theirs.rebase(&base, &ours).unwrap();
+        let sha1 = |state: &State, path: &str| state.files.get_file(path).unwrap().as_file().unwrap().sha1.to_owned();
+
+        assert_eq!(sha1(&theirs, "kept"), "bb");
+        assert_eq!(sha1(&theirs, "both-changed"), "cc");
+        assert_eq!(sha1(&theirs, "theirs-added"), "bb");
+        assert!(theirs.files.get_file("ours-removed").is_none());
+        assert_eq!(theirs.files.get_file("dir/sub/link").unwrap().as_symlink().unwrap().target, "../inner");
+        assert!(theirs.files.get_file("dir/inner").is_some());
+        // 被别人删掉的上级目录会重新创建，但是里面别的文件不会回来
+        assert!(theirs.files.get_file("theirs-removed/sub/link").is_some());
+        assert!(theirs.files.get_file("theirs-removed/x").is_none());
+    }
+}
@@ -0,0 +1,109 @@
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::RecvTimeoutError;
+use std::time::Duration;
+
+use notify::Event;
+use notify::EventKind;
+use notify::RecommendedWatcher;
+use notify::RecursiveMode;
+use notify::Watcher;
+
+use crate::AppResult;
+use crate::file::File;
+
+/// 检查退出标记的间隔
+const POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// 文件变动监视器，对一个或多个源目录进行递归监视(Linux下基于inotify)
+pub struct FileWatcher {
+    receiver: Receiver<notify::Result<Event>>,
+    /// 被监视的目录，需要重新扫描时会把整个目录当作发生了变动
+    directories: Vec<File>,
+    _watcher: RecommendedWatcher,
+}
+
+impl FileWatcher {
+    pub fn new(directories: &[&File]) -> AppResult<FileWatcher> {
+        let (sender, receiver) = mpsc::channel();
+        let mut watcher = notify::recommended_watcher(sender)?;
+        for directory in directories {
+            watcher.watch(directory.get_raw(), RecursiveMode::Recursive)?;
+        }
+
+        Ok(FileWatcher { receiver, directories: directories.iter().map(|d| (*d).clone()).collect(), _watcher: watcher })
+    }
+
+    /// 阻塞等待下一批文件变动，返回发生变动的文件(已去重)
+    ///
+    /// 收到第一个事件后，会一直等到连续debounce时间内没有新的事件才返回，以合并突发的大量事件<br/>
+    /// running被置为false时返回None
+    pub fn wait_for_changes(&self, debounce: Duration, running: &AtomicBool) -> AppResult<Option<Vec<File>>> {
+        let mut changes: Vec<File> = Vec::new();
+
+        // 等待第一个事件
+        while changes.is_empty() {
+            if !running.load(Ordering::SeqCst) {
+                return Ok(None);
+            }
+
+            match self.receiver.recv_timeout(POLL_INTERVAL) {
+                Ok(event) => self.collect(event, &mut changes),
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => return Ok(None),
+            }
+        }
+
+        // 合并后续的事件，直到安静下来
+        loop {
+            match self.receiver.recv_timeout(debounce) {
+                Ok(event) => self.collect(event, &mut changes),
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+
+            if !running.load(Ordering::SeqCst) {
+                return Ok(None);
+            }
+        }
+
+        Ok(Some(changes))
+    }
+
+    fn collect(&self, event: notify::Result<Event>, changes: &mut Vec<File>) {
+        let event = match event {
+            Ok(event) => event,
+            Err(e) => {
+                println!("监视文件变动时出现错误，重新扫描所有的源目录: {}", e);
+                self.add_changes(self.directories.iter().cloned(), changes);
+                return;
+            },
+        };
+
+        // 只读访问不会改变文件内容
+        if let EventKind::Access(_) = event.kind {
+            return;
+        }
+
+        // 事件队列溢出(Rescan)等情况下，事件里通常没有路径，这时无法知道哪些文件变了，只能重新扫描整个源目录
+        if event.paths.is_empty() {
+            if event.need_rescan() {
+                println!("文件变动事件溢出，重新扫描所有的源目录");
+            }
+            self.add_changes(self.directories.iter().cloned(), changes);
+            return;
+        }
+
+        self.add_changes(event.paths.into_iter().map(File::from), changes);
+    }
+
+    fn add_changes(&self, files: impl Iterator<Item = File>, changes: &mut Vec<File>) {
+        for file in files {
+            if !changes.iter().any(|c| c.get_raw() == file.get_raw()) {
+                changes.push(file);
+            }
+        }
+    }
+}
\ No newline at end of file
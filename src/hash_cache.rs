@@ -33,4 +33,9 @@ impl HashCache {
 
         map.get(relative_path).unwrap().to_owned()
     }
+
+    /// 清空所有缓存的hash，文件内容可能发生变化时使用
+    pub fn clear(&self) {
+        self.cache.lock().unwrap().get_mut().clear();
+    }
 }
\ No newline at end of file
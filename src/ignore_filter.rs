@@ -0,0 +1,187 @@
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Result;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+use ignore::Match;
+use ignore::gitignore::Gitignore;
+use ignore::gitignore::Glob;
+use ignore::gitignore::GitignoreBuilder;
+
+use crate::AppResult;
+use crate::file::File;
+use crate::utils::get_dirname;
+
+/// 每个目录下的忽略规则文件名
+pub const IGNORE_FILE_NAME: &str = ".uploadignore";
+
+/// gitignore风格的文件过滤器
+///
+/// 规则来自配置文件里的ignore-patterns，以及源目录里各级目录下的.uploadignore文件。
+/// 越深的目录里的.uploadignore优先级越高，同一个文件里后面的规则优先于前面的规则，
+/// 配置文件里的规则优先级最低。一个目录被忽略后，它下面的所有文件也都会被忽略
+pub struct IgnoreFilter {
+    base_path: File,
+    patterns: Gitignore,
+    /// 每个目录下的.uploadignore文件，没有规则文件的目录为None
+    directories: Mutex<Cell<HashMap<String, Option<Arc<Gitignore>>>>>,
+}
+
+impl IgnoreFilter {
+    pub fn new(base_path: &File, patterns: &[String]) -> AppResult<IgnoreFilter> {
+        let mut builder = GitignoreBuilder::new(base_path.get_raw());
+        for pattern in patterns {
+            builder.add_line(None, pattern)?;
+        }
+
+        let patterns = builder.build()?;
+
+        Ok(IgnoreFilter { base_path: base_path.clone(), patterns, directories: Mutex::new(Cell::new(HashMap::new())) })
+    }
+
+    /// 判断一个路径是否被忽略
+    ///
+    /// relative_path: 源目录里的相对路径<br/>
+    /// is_dir: 这个路径是否是一个目录
+    pub fn is_ignored(&self, relative_path: &str, is_dir: bool) -> Result<bool> {
+        Ok(self.explain(relative_path, is_dir)?.is_some())
+    }
+
+    /// 判断一个路径是否被忽略，被忽略时返回导致它被忽略的规则
+    ///
+    /// .uploadignore文件读取或者解析失败时返回错误，不能当作没有规则，把本来要排除的文件上传掉
+    pub fn explain(&self, relative_path: &str, is_dir: bool) -> Result<Option<String>> {
+        let relative_path = relative_path.replace("\\", "/");
+
+        if relative_path.is_empty() || relative_path == "." {
+            return Ok(None);
+        }
+
+        // 规则文件本身不需要上传
+        if !is_dir && crate::utils::get_basename(&relative_path) == IGNORE_FILE_NAME {
+            return Ok(Some(format!("{} file itself", IGNORE_FILE_NAME)));
+        }
+
+        // 上级目录被忽略时，下面的所有文件也都被忽略
+        let mut parent = get_dirname(&relative_path);
+        while let Some(dir) = parent {
+            if let Some(rule) = self.matched(dir, true)? {
+                return Ok(Some(format!("{} of parent directory '{}'", rule, dir)));
+            }
+            parent = get_dirname(dir);
+        }
+
+        self.matched(&relative_path, is_dir)
+    }
+
+    /// 清空已经读取的.uploadignore文件，文件内容可能发生变化时使用
+    pub fn clear(&self) {
+        self.directories.lock().unwrap().get_mut().clear();
+    }
+
+    /// 从最深的一级目录开始往上逐级匹配，第一个有结果的规则文件决定是否被忽略
+    fn matched(&self, relative_path: &str, is_dir: bool) -> Result<Option<String>> {
+        let mut directory = get_dirname(relative_path);
+
+        loop {
+            let dir = directory.unwrap_or("");
+            let path_in_dir = if dir.is_empty() { relative_path } else { &relative_path[dir.len() + 1..] };
+
+            if let Some(gitignore) = self.load_directory(dir)? {
+                match gitignore.matched(path_in_dir, is_dir) {
+                    Match::Ignore(glob) => return Ok(Some(IgnoreFilter::describe(glob))),
+                    Match::Whitelist(_) => return Ok(None),
+                    Match::None => {},
+                }
+            }
+
+            if directory.is_none() {
+                break;
+            }
+            directory = get_dirname(dir);
+        }
+
+        Ok(match self.patterns.matched(relative_path, is_dir) {
+            Match::Ignore(glob) => Some(IgnoreFilter::describe(glob)),
+            _ => None,
+        })
+    }
+
+    fn describe(glob: &Glob) -> String {
+        match glob.from() {
+            Some(file) => format!("'{}' in {}", glob.original(), file.to_string_lossy()),
+            None => format!("'{}' in ignore-patterns", glob.original()),
+        }
+    }
+
+    /// 读取某个目录下的.uploadignore文件，读取过的文件会被缓存起来
+    fn load_directory(&self, relative_dir: &str) -> Result<Option<Arc<Gitignore>>> {
+        let mut directories = self.directories.lock().unwrap();
+        let directories = directories.get_mut();
+
+        // 只复制Arc，不复制里面的规则
+        if let Some(gitignore) = directories.get(relative_dir) {
+            return Ok(gitignore.clone());
+        }
+
+        let gitignore = self.read_ignore_file(relative_dir)?.map(Arc::new);
+        directories.insert(relative_dir.to_owned(), gitignore.clone());
+
+        Ok(gitignore)
+    }
+
+    fn read_ignore_file(&self, relative_dir: &str) -> Result<Option<Gitignore>> {
+        let dir = if relative_dir.is_empty() { self.base_path.clone() } else { self.base_path.append(relative_dir)? };
+        let ignore_file = dir.append(IGNORE_FILE_NAME)?;
+
+        if !ignore_file.is_file() {
+            return Ok(None);
+        }
+
+        let mut builder = GitignoreBuilder::new(dir.get_raw());
+        if let Some(err) = builder.add(ignore_file.get_raw()) {
+            return Err(Error::new(ErrorKind::InvalidData, format!("invalid {} file: {}", IGNORE_FILE_NAME, err)));
+        }
+
+        let gitignore = builder.build()
+            .map_err(|e| Error::new(ErrorKind::InvalidData, format!("failed to parse {}: {}", ignore_file.path(), e)))?;
+
+        Ok(Some(gitignore))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TempDir;
+
+    #[test]
+    fn uploadignore_rules() {
+        let temp = TempDir::new("ignore");
+        temp.file("sub").mkdirs().unwrap();
+        temp.file("sub/.uploadignore").write_atomically("*.log\n!keep.log\n").unwrap();
+
+        let filter = IgnoreFilter::new(&temp.dir, &["*.tmp".to_owned()]).unwrap();
+        assert!(filter.is_ignored("sub/a.log", false).unwrap());
+        assert!(!filter.is_ignored("sub/keep.log", false).unwrap());
+        assert!(!filter.is_ignored("a.log", false).unwrap());
+        assert!(filter.is_ignored("a.tmp", false).unwrap());
+        assert!(filter.is_ignored("sub/.uploadignore", false).unwrap());
+    }
+
+    #[test]
+    fn invalid_uploadignore_is_an_error() {
+        let temp = TempDir::new("ignore");
+        temp.file("sub").mkdirs().unwrap();
+        temp.file("sub/.uploadignore").write_atomically("secret\n{a,b\n").unwrap();
+
+        let filter = IgnoreFilter::new(&temp.dir, &[]).unwrap();
+        assert!(filter.is_ignored("sub/secret", false).is_err());
+        assert!(filter.explain("sub/deeper/file", false).is_err());
+        // 没有规则文件的目录不受影响
+        assert!(!filter.is_ignored("other", false).unwrap());
+    }
+}
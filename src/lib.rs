@@ -12,5 +12,24 @@ pub mod file_state;
 pub mod differences;
 pub mod hash_cache;
 pub mod rule_filter;
+pub mod file_watcher;
+pub mod source;
+pub mod path_mapping;
+pub mod command_rule;
+pub mod ignore_filter;
+pub mod file_selector;
+pub mod symlink_policy;
+pub mod file_metadata;
+pub mod comparison;
+pub mod release;
+pub mod manifest;
+pub mod signing;
+pub mod snapshot;
+pub mod state_diff;
+pub mod state_format;
+pub mod state_lock;
+
+#[cfg(test)]
+mod test_utils;
 
 pub type AppResult<R> = std::result::Result<R, Box<dyn std::error::Error>>;
\ No newline at end of file
@@ -3,10 +3,28 @@ use std::process;
 
 use backtrace::Backtrace;
 use incremental_upload::AppResult;
+use incremental_upload::app_options::AppCommand;
+use incremental_upload::app_options::AppOptions;
+use incremental_upload::app_options::StateCommand;
 use incremental_upload::application::App;
 
 fn run() -> AppResult<()> {
-    App::new()?.main()
+    let options = AppOptions::parse_from_command_line();
+
+    // 比较两个状态文件时不需要配置文件
+    if let AppCommand::State(StateCommand::Diff { old, new, format }) = &options.command {
+        return App::diff_state_files(old, new, format);
+    }
+
+    for config in App::load_configs(&options)? {
+        if let Some(target) = &config.target {
+            println!("======== {} ========", target);
+        }
+
+        App::new(options.clone(), config)?.main()?;
+    }
+
+    Ok(())
 }
 
 fn main() {
@@ -24,8 +42,11 @@ fn main() {
         
         println!("{}\n", panic_info);
         println!("程序发生错误, 以上为错误详情");
-        process::exit(1);
+
+        // 不在这里退出，继续展开让持有的锁被释放。线程池里的panic会被转换成错误返回给主线程，主线程上的panic由main函数退出
     }));
 
-    run().unwrap();
+    if panic::catch_unwind(|| run().unwrap()).is_err() {
+        process::exit(1);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,101 @@
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Result;
+
+use json::JsonValue;
+use json::object;
+
+use crate::app_config::ManifestConfig;
+use crate::file_state::State;
+use crate::path_mapping::PathMapper;
+use crate::simple_file::DirData;
+use crate::simple_file::SimpleFile;
+use crate::variable_replace::VariableReplace;
+
+/// 清单文件的格式
+pub enum ManifestFormat {
+    /// 和状态文件相同的嵌套结构
+    Nested,
+    /// 所有文件平铺成一个列表，路径为远端路径
+    Flat,
+    /// 使用自定义的模板逐个渲染每一个文件
+    Template,
+}
+
+impl ManifestFormat {
+    pub fn parse(text: &str) -> Result<ManifestFormat> {
+        match text {
+            "nested" => Ok(ManifestFormat::Nested),
+            "flat" => Ok(ManifestFormat::Flat),
+            "template" => Ok(ManifestFormat::Template),
+            _ => Err(Error::new(ErrorKind::InvalidInput, format!("unknown format of manifest: {}", text))),
+        }
+    }
+}
+
+/// 把状态渲染成给客户端使用的更新清单
+pub struct Manifest<'a> {
+    format: ManifestFormat,
+    config: &'a ManifestConfig,
+    mapper: &'a PathMapper,
+}
+
+impl Manifest<'_> {
+    pub fn new<'a>(config: &'a ManifestConfig, mapper: &'a PathMapper) -> Result<Manifest<'a>> {
+        Ok(Manifest { format: ManifestFormat::parse(&config.format)?, config, mapper })
+    }
+
+    /// 渲染清单内容
+    ///
+    /// state: 要渲染的状态<br/>
+    /// vars: 模板里可以使用的全局变量<br/>
+    /// indent: json格式的缩进，为0时不缩进
+    pub fn render(&self, state: &State, vars: &VariableReplace, indent: u16) -> String {
+        let dump = |json: JsonValue| if indent > 0 { json.pretty(indent) } else { json.dump() };
+
+        match self.format {
+            ManifestFormat::Nested => dump(state.to_json_array()),
+            ManifestFormat::Flat => {
+                let mut array = JsonValue::new_array();
+                self.walk(&state.files, "", &mut |path, file| {
+                    let remote_path = self.mapper.map(path);
+                    let entry = if let Some(f) = file.as_file() {
+                        object! { path: remote_path, length: f.length, hash: f.sha1.to_owned(), modified: f.modified }
+                    } else if let Some(l) = file.as_symlink() {
+                        object! { path: remote_path, link: l.target.to_owned() }
+                    } else {
+                        return;
+                    };
+                    array.push(entry).unwrap();
+                });
+                dump(array)
+            },
+            ManifestFormat::Template => {
+                let mut items = Vec::<String>::new();
+                self.walk(&state.files, "", &mut |path, file| {
+                    if let Some(f) = file.as_file() {
+                        let mut vars = vars.to_owned();
+                        vars.add("path", path);
+                        vars.add("remote-path", &self.mapper.map(path));
+                        vars.add("length", &f.length.to_string());
+                        vars.add("hash", &f.sha1);
+                        vars.add("modified", &f.modified.to_string());
+                        items.push(vars.apply(&self.config.item));
+                    }
+                });
+                vars.apply(&self.config.header) + &items.join(&self.config.separator) + &vars.apply(&self.config.footer)
+            },
+        }
+    }
+
+    /// 按照状态里的顺序遍历所有的文件和符号链接(不包括目录)
+    fn walk(&self, dir: &DirData, parent: &str, visit: &mut dyn FnMut(&str, &SimpleFile)) {
+        for f in &dir.files {
+            let path = if parent.is_empty() { f.name.to_owned() } else { parent.to_owned() + "/" + &f.name };
+            match f.as_dir() {
+                Some(d) => self.walk(d, &path, visit),
+                None => visit(&path, f),
+            }
+        }
+    }
+}
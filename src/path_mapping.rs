@@ -0,0 +1,70 @@
+use std::io::Error;
+use std::io::ErrorKind;
+
+use regex::Captures;
+use regex::Regex;
+
+use crate::AppResult;
+use crate::app_config::PathMappingConfig;
+
+/// 替换后的大小写转换
+pub enum LetterCase {
+    Unchanged,
+    Lower,
+    Upper,
+}
+
+pub struct PathMapping {
+    pub pattern: Regex,
+    pub replace: String,
+    pub case: LetterCase,
+}
+
+/// 将本地的相对路径转换为远端路径，所有映射规则按顺序依次作用在上一条规则的结果上
+pub struct PathMapper {
+    pub mappings: Vec<PathMapping>
+}
+
+impl PathMapper {
+    pub fn new(configs: &[PathMappingConfig]) -> AppResult<PathMapper> {
+        // 预编译正则表达式
+        let mut mappings = Vec::<PathMapping>::new();
+        for config in configs {
+            let pat = Regex::new(&config.pattern);
+            if pat.is_err() {
+                let msg = pat.err().unwrap().to_string() + " (all single-backslashes may be escaped as double for display purpose)";
+                return Err(Box::new(Error::new(ErrorKind::InvalidInput, msg)));
+            }
+
+            let case = match &config.case[..] {
+                "" => LetterCase::Unchanged,
+                "lower" => LetterCase::Lower,
+                "upper" => LetterCase::Upper,
+                other => return Err(Box::new(Error::new(ErrorKind::InvalidInput, format!("unknown case of path-mappings: {}", other)))),
+            };
+
+            mappings.push(PathMapping { pattern: pat.unwrap(), replace: config.replace.to_owned(), case });
+        }
+
+        Ok(PathMapper { mappings })
+    }
+
+    pub fn map(&self, path: &str) -> String {
+        let mut result = path.to_owned();
+
+        for mapping in &self.mappings {
+            result = mapping.pattern.replace_all(&result, |caps: &Captures| {
+                let mut replaced = String::new();
+                caps.expand(&mapping.replace, &mut replaced);
+
+                match mapping.case {
+                    LetterCase::Unchanged => replaced,
+                    LetterCase::Lower => replaced.to_lowercase(),
+                    LetterCase::Upper => replaced.to_uppercase(),
+                }
+            }).into_owned();
+        }
+
+        result
+    }
+}
\ No newline at end of file
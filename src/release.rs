@@ -0,0 +1,90 @@
+use json::JsonValue;
+use json::object;
+
+use crate::AppResult;
+use crate::file::File;
+
+/// 一次已经发布的版本
+pub struct Release {
+    /// 版本名称
+    pub name: String,
+    /// 这个版本的文件所在的暂存前缀
+    pub staging: String,
+    /// 发布时间(unix时间戳，秒)
+    pub time: u64,
+}
+
+/// 本地记录的发布历史，按发布时间从旧到新排列
+pub struct ReleaseHistory {
+    file: File,
+    pub releases: Vec<Release>,
+}
+
+impl ReleaseHistory {
+    /// 从本地文件读取发布历史，文件不存在时返回空的历史
+    pub fn load(file: File) -> AppResult<ReleaseHistory> {
+        let mut releases = Vec::<Release>::new();
+
+        if file.exists() {
+            let json = json::parse(&file.read()?)?;
+            for r in json.members() {
+                if let (Some(name), Some(staging)) = (r["release"].as_str(), r["staging"].as_str()) {
+                    releases.push(Release { name: name.to_owned(), staging: staging.to_owned(), time: r["time"].as_u64().unwrap_or(0) });
+                }
+            }
+        }
+
+        Ok(ReleaseHistory { file, releases })
+    }
+
+    pub fn save(&self) -> AppResult<()> {
+        let mut array = JsonValue::new_array();
+        for r in &self.releases {
+            array.push(object! {
+                release: r.name.to_owned(),
+                staging: r.staging.to_owned(),
+                time: r.time,
+            })?;
+        }
+
+        if self.file.exists() {
+            self.file.rm()?;
+        }
+        if let Some(parent) = self.file.parent()? {
+            parent.mkdirs()?;
+        }
+        self.file.write(&array.pretty(2))?;
+
+        Ok(())
+    }
+
+    pub fn find(&self, name: &str) -> Option<&Release> {
+        self.releases.iter().find(|r| r.name == name)
+    }
+
+    /// 记录一个新发布的版本，同名的旧记录会被移除
+    pub fn add(&mut self, release: Release) {
+        self.releases.retain(|r| r.name != release.name);
+        self.releases.push(release);
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<Release> {
+        let index = self.releases.iter().position(|r| r.name == name)?;
+        Some(self.releases.remove(index))
+    }
+
+    /// 只保留最新的keep个版本时，需要被清理掉的旧版本(从旧到新)，keep为0时保留所有的版本
+    pub fn expired(&self, keep: usize) -> Vec<Release> {
+        if keep == 0 || self.releases.len() <= keep {
+            return Vec::new();
+        }
+
+        self.releases[..self.releases.len() - keep].to_vec()
+    }
+}
+
+impl Clone for Release {
+    fn clone(&self) -> Self {
+        Self { name: self.name.clone(), staging: self.staging.clone(), time: self.time }
+    }
+}
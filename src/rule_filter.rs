@@ -1,48 +1,356 @@
+use std::fs;
 use std::io::Error;
 use std::io::ErrorKind;
+use std::time::SystemTime;
 
 use regex::Regex;
 
 use crate::AppResult;
+use crate::file::File;
+
+/// 文件类型，用于@type条件
+pub enum FileKind {
+    File,
+    Dir,
+    Symlink,
+}
+
+/// 一条过滤规则，可以是路径的正则表达式，也可以是以@开头的文件属性条件
+pub enum Rule {
+    /// 路径的正则表达式
+    Pattern(Regex),
+    /// @size>N：文件大小大于N字节
+    SizeGreater(u64),
+    /// @size<N：文件大小小于N字节
+    SizeLess(u64),
+    /// @min-age=N：文件至少在N秒之前被修改过
+    MinAge(u64),
+    /// @max-age=N：文件在最近N秒内被修改过
+    MaxAge(u64),
+    /// @type=file|dir|symlink：文件类型
+    Type(FileKind),
+    /// @empty：空文件或者空目录
+    Empty,
+}
+
+impl Rule {
+    fn parse(text: &str) -> AppResult<Rule> {
+        let attribute = match text.strip_prefix('@') {
+            Some(attribute) => attribute,
+            None => {
+                let pat = Regex::new(text);
+                if pat.is_err() {
+                    let msg = pat.err().unwrap().to_string() + " (all single-backslashes may be escaped as double for display purpose)";
+                    return Err(Box::new(Error::new(ErrorKind::InvalidInput, msg)));
+                }
+                return Ok(Rule::Pattern(pat.unwrap()));
+            },
+        };
+
+        let invalid = || Box::new(Error::new(ErrorKind::InvalidInput, format!("invalid attribute filter: {}", text)));
+
+        if attribute == "empty" {
+            Ok(Rule::Empty)
+        } else if let Some(size) = attribute.strip_prefix("size>") {
+            Ok(Rule::SizeGreater(parse_size(size).ok_or_else(invalid)?))
+        } else if let Some(size) = attribute.strip_prefix("size<") {
+            Ok(Rule::SizeLess(parse_size(size).ok_or_else(invalid)?))
+        } else if let Some(age) = attribute.strip_prefix("min-age=") {
+            Ok(Rule::MinAge(parse_duration(age).ok_or_else(invalid)?))
+        } else if let Some(age) = attribute.strip_prefix("max-age=") {
+            Ok(Rule::MaxAge(parse_duration(age).ok_or_else(invalid)?))
+        } else if let Some(kind) = attribute.strip_prefix("type=") {
+            match kind {
+                "file" => Ok(Rule::Type(FileKind::File)),
+                "dir" => Ok(Rule::Type(FileKind::Dir)),
+                "symlink" => Ok(Rule::Type(FileKind::Symlink)),
+                _ => Err(invalid()),
+            }
+        } else {
+            Err(invalid())
+        }
+    }
+
+    /// 判断是否满足这条规则，规则不适用时返回None(比如文件已经不存在了，或者对目录判断文件大小)
+    fn test(&self, text: &str, file: Option<&File>) -> Option<bool> {
+        if let Rule::Pattern(reg) = self {
+            return Some(reg.is_match(text));
+        }
+
+        let file = file?;
+        let link_metadata = fs::symlink_metadata(file.get_raw()).ok()?;
+
+        if let Rule::Type(kind) = self {
+            let file_type = link_metadata.file_type();
+            // 目录只适用@type=dir，其它类型的条件只对文件生效
+            if file_type.is_dir() && !matches!(kind, FileKind::Dir) {
+                return None;
+            }
+            return Some(match kind {
+                FileKind::File => file_type.is_file(),
+                FileKind::Dir => file_type.is_dir(),
+                FileKind::Symlink => file_type.is_symlink(),
+            });
+        }
+
+        // 其它条件判断的是符号链接所指向的文件
+        let metadata = fs::metadata(file.get_raw()).ok()?;
+
+        match self {
+            Rule::SizeGreater(size) => if metadata.is_file() { Some(metadata.len() > *size) } else { None },
+            Rule::SizeLess(size) => if metadata.is_file() { Some(metadata.len() < *size) } else { None },
+            Rule::Empty => if metadata.is_dir() {
+                Some(file.files().ok()?.next().is_none())
+            } else {
+                Some(metadata.len() == 0)
+            },
+            // 目录的修改时间会随着里面的文件变化，时间条件只对文件生效
+            Rule::MinAge(min) => if metadata.is_dir() { None } else { Some(file_age(&metadata)? >= *min) },
+            Rule::MaxAge(max) => if metadata.is_dir() { None } else { Some(file_age(&metadata)? <= *max) },
+            _ => None,
+        }
+    }
+}
+
+pub struct FilterRule {
+    pub rule: Rule,
+    pub reversed: bool,
+    pub text: String,
+}
 
 pub struct RuleFilter {
-    pub filters: Vec<(Regex, bool)>
+    pub filters: Vec<FilterRule>
 }
 
 impl RuleFilter {
     pub fn new(rules: &Vec<String>) -> AppResult<RuleFilter> {
         // 预编译正则表达式
-        let mut regexes_compiled = Vec::<(Regex, bool)>::new();
+        let mut regexes_compiled = Vec::<FilterRule>::new();
         for pattern in rules {
             let mut pattern = pattern.to_string();
             let reversed = pattern.starts_with("!");
             if reversed {
                 pattern = ((&pattern)[1..]).to_owned();
             }
-            let pat = Regex::new(&pattern);
-            if pat.is_err() {
-                let msg = pat.err().unwrap().to_string() + " (all single-backslashes may be escaped as double for display purpose)";
-                return Err(Box::new(Error::new(ErrorKind::InvalidInput, msg)));
-            }
-            regexes_compiled.push((pat.unwrap(), reversed));
+            regexes_compiled.push(FilterRule { rule: Rule::parse(&pattern)?, reversed, text: pattern });
         }
 
         Ok(RuleFilter { filters: regexes_compiled })
     }
 
-    pub fn test_any(&self, text: &str, if_empty: bool) -> bool {
-        if self.filters.is_empty() {
-            return if_empty;
+    /// 去掉和时间有关的条件(@min-age、@max-age)，这些条件排除掉的文件过一段时间之后会重新被选中
+    pub fn without_transient_rules(mut self) -> RuleFilter {
+        self.filters.retain(|f| !matches!(f.rule, Rule::MinAge(_) | Rule::MaxAge(_)));
+        self
+    }
+
+    /// 任意一条规则满足时返回true
+    ///
+    /// file: 路径对应的本地文件，用于判断文件属性条件，为None时所有的属性条件都会被跳过<br/>
+    /// if_empty: 没有任何适用的规则时返回的值
+    pub fn test_any(&self, text: &str, file: Option<&File>, if_empty: bool) -> bool {
+        let mut applicable = false;
+        for (_, matched) in self.results(text, file) {
+            if matched {
+                return true;
+            }
+            applicable = true;
+        }
+
+        if applicable { false } else { if_empty }
+    }
+
+    /// 所有的规则都满足时返回true
+    ///
+    /// file: 路径对应的本地文件，用于判断文件属性条件，为None时所有的属性条件都会被跳过<br/>
+    /// if_empty: 没有任何适用的规则时返回的值
+    pub fn test_all(&self, text: &str, file: Option<&File>, if_empty: bool) -> bool {
+        let mut applicable = false;
+        for (_, matched) in self.results(text, file) {
+            if !matched {
+                return false;
+            }
+            applicable = true;
         }
 
-        self.filters.iter().any(|(reg, reversed)| reg.is_match(text) != *reversed)
+        if applicable { true } else { if_empty }
+    }
+
+    /// 找到第一条满足的规则，用来解释test_any的结果
+    pub fn find_matched(&self, text: &str, file: Option<&File>) -> Option<String> {
+        self.results(text, file)
+            .find(|(_, matched)| *matched)
+            .map(|(filter, _)| RuleFilter::describe(filter))
+    }
+
+    /// 找到第一条不满足的规则，用来解释test_all的结果
+    pub fn find_unmatched(&self, text: &str, file: Option<&File>) -> Option<String> {
+        self.results(text, file)
+            .find(|(_, matched)| !*matched)
+            .map(|(filter, _)| RuleFilter::describe(filter))
+    }
+
+    /// 依次判断每一条适用的规则
+    fn results<'a>(&'a self, text: &'a str, file: Option<&'a File>) -> impl Iterator<Item = (&'a FilterRule, bool)> + 'a {
+        self.filters.iter()
+            .filter_map(move |f| f.rule.test(text, file).map(|matched| (f, matched != f.reversed)))
+    }
+
+    fn describe(filter: &FilterRule) -> String {
+        format!("'{}{}'", if filter.reversed { "!" } else { "" }, filter.text)
+    }
+}
+
+/// 文件距离上次修改过去了多少秒
+fn file_age(metadata: &fs::Metadata) -> Option<u64> {
+    Some(SystemTime::now().duration_since(metadata.modified().ok()?).map_or_else(|_e| 0, |v| v.as_secs()))
+}
+
+/// 解析文件大小，支持K、M、G、T后缀(1024进制)
+fn parse_size(text: &str) -> Option<u64> {
+    let text = text.trim().to_uppercase();
+    let text = text.strip_suffix('B').unwrap_or(&text);
+    let (number, unit) = match text.chars().last()? {
+        'K' => (&text[..text.len() - 1], 1u64 << 10),
+        'M' => (&text[..text.len() - 1], 1u64 << 20),
+        'G' => (&text[..text.len() - 1], 1u64 << 30),
+        'T' => (&text[..text.len() - 1], 1u64 << 40),
+        _ => (text, 1),
+    };
+
+    number.trim().parse::<u64>().ok()?.checked_mul(unit)
+}
+
+/// 解析时长(秒)，支持s、m、h、d后缀
+fn parse_duration(text: &str) -> Option<u64> {
+    let text = text.trim();
+    let (number, unit) = match text.chars().last()? {
+        's' => (&text[..text.len() - 1], 1),
+        'm' => (&text[..text.len() - 1], 60),
+        'h' => (&text[..text.len() - 1], 3600),
+        'd' => (&text[..text.len() - 1], 86400),
+        _ => (text, 1),
+    };
+
+    number.trim().parse::<u64>().ok()?.checked_mul(unit)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TempDir;
+
+    fn filter(rules: &[&str]) -> AppResult<RuleFilter> {
+        RuleFilter::new(&rules.iter().map(|r| r.to_string()).collect())
+    }
+
+    #[test]
+    fn parse_sizes() {
+        assert_eq!(parse_size("100"), Some(100));
+        assert_eq!(parse_size("2K"), Some(2048));
+        assert_eq!(parse_size("2kb"), Some(2048));
+        assert_eq!(parse_size(" 3 M "), Some(3 << 20));
+        assert_eq!(parse_size("1G"), Some(1 << 30));
+        assert_eq!(parse_size("1T"), Some(1 << 40));
+        assert_eq!(parse_size(""), None);
+        assert_eq!(parse_size("K"), None);
+        assert_eq!(parse_size("-1"), None);
+        assert_eq!(parse_size("1.5M"), None);
+    }
+
+    #[test]
+    fn parse_durations() {
+        assert_eq!(parse_duration("30"), Some(30));
+        assert_eq!(parse_duration("30s"), Some(30));
+        assert_eq!(parse_duration("5m"), Some(300));
+        assert_eq!(parse_duration("2h"), Some(7200));
+        assert_eq!(parse_duration("1d"), Some(86400));
+        assert_eq!(parse_duration("1w"), None);
+        assert_eq!(parse_duration(""), None);
     }
-    
-    pub fn test_all(&self, text: &str, if_empty: bool) -> bool {
-        if self.filters.is_empty() {
-            return if_empty;
+
+    #[test]
+    fn overflow_is_rejected() {
+        assert_eq!(parse_size("99999999999G"), None);
+        assert_eq!(parse_size("16777216T"), None);
+        assert_eq!(parse_size("16777215T"), Some(16777215u64 << 40));
+        assert_eq!(parse_duration("999999999999999999d"), None);
+
+        assert!(filter(&["@size>99999999999G"]).is_err());
+        assert!(filter(&["@max-age=999999999999999999d"]).is_err());
+    }
+
+    #[test]
+    fn parse_rules() {
+        let rules = filter(&["\\.log$", "!@size>1M", "@min-age=1h", "@max-age=2d", "@type=symlink", "@empty"]).unwrap();
+        let rules = rules.filters;
+
+        assert!(matches!(rules[0].rule, Rule::Pattern(_)));
+        assert!(!rules[0].reversed);
+        assert!(matches!(rules[1].rule, Rule::SizeGreater(1048576)));
+        assert!(rules[1].reversed);
+        assert_eq!(rules[1].text, "@size>1M");
+        assert!(matches!(rules[2].rule, Rule::MinAge(3600)));
+        assert!(matches!(rules[3].rule, Rule::MaxAge(172800)));
+        assert!(matches!(rules[4].rule, Rule::Type(FileKind::Symlink)));
+        assert!(matches!(rules[5].rule, Rule::Empty));
+    }
+
+    #[test]
+    fn invalid_rules_are_rejected() {
+        for rule in ["@size", "@size>", "@size=1M", "@type=socket", "@age>1d", "@", "(unclosed"] {
+            assert!(filter(&[rule]).is_err(), "{}", rule);
         }
+    }
+
+    #[test]
+    fn transient_rules_are_removed() {
+        let rules = filter(&["@min-age=1h", "\\.tmp$", "@max-age=1d", "@size<1K"]).unwrap().without_transient_rules();
+        assert_eq!(rules.filters.iter().map(|f| &f.text[..]).collect::<Vec<&str>>(), vec!["\\.tmp$", "@size<1K"]);
+    }
+
+    #[test]
+    fn attribute_rules_are_skipped_without_file() {
+        let rules = filter(&["@size>1K", "\\.log$"]).unwrap();
+        assert!(rules.test_any("a.log", None, false));
+        assert!(!rules.test_any("a.txt", None, true));
+        assert!(rules.test_all("a.log", None, false));
+        assert_eq!(rules.find_matched("a.log", None), Some("'\\.log$'".to_owned()));
 
-        self.filters.iter().all(|(reg, reversed)| reg.is_match(text) != *reversed)
+        let rules = filter(&["@size>1K"]).unwrap();
+        assert!(rules.test_any("a.log", None, true));
+        assert!(!rules.test_all("a.log", None, false));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn attribute_rules_on_files_and_dirs() {
+        let temp = TempDir::new("rules");
+        let big = temp.file("big.bin");
+        big.write_atomically(vec![0u8; 2048]).unwrap();
+        let empty = temp.file("empty.txt");
+        empty.write_atomically("").unwrap();
+        let sub = temp.file("sub");
+        sub.mkdirs().unwrap();
+
+        let rules = filter(&["@size>1K"]).unwrap();
+        assert!(rules.test_any("big.bin", Some(&big), false));
+        assert!(!rules.test_any("empty.txt", Some(&empty), true));
+        // 大小条件不适用于目录
+        assert!(rules.test_any("sub", Some(&sub), true));
+
+        let rules = filter(&["@empty"]).unwrap();
+        assert!(rules.test_any("empty.txt", Some(&empty), false));
+        assert!(rules.test_any("sub", Some(&sub), false));
+        assert!(!rules.test_any("big.bin", Some(&big), true));
+
+        // 目录只适用@type=dir
+        assert!(filter(&["@type=file"]).unwrap().test_any("sub", Some(&sub), true));
+        assert!(filter(&["@type=dir"]).unwrap().test_any("sub", Some(&sub), false));
+        assert!(!filter(&["@type=dir"]).unwrap().test_any("big.bin", Some(&big), true));
+
+        // 时间条件不适用于目录
+        assert!(filter(&["@max-age=1d"]).unwrap().test_any("big.bin", Some(&big), false));
+        assert!(!filter(&["@min-age=1d"]).unwrap().test_any("big.bin", Some(&big), true));
+        assert!(filter(&["@min-age=1d"]).unwrap().test_any("sub", Some(&sub), true));
+    }
+}
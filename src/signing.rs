@@ -0,0 +1,178 @@
+use std::env;
+use std::fs;
+use std::io::Error;
+use std::io::ErrorKind;
+
+use ed25519_dalek::Signature;
+use ed25519_dalek::Signer;
+use ed25519_dalek::SigningKey;
+use ed25519_dalek::Verifier;
+use ed25519_dalek::VerifyingKey;
+
+use crate::AppResult;
+use crate::file::File;
+
+/// 签名文件的后缀，签名保存在被签名的文件旁边
+pub const SIGNATURE_SUFFIX: &str = ".sig";
+
+/// 使用Ed25519对状态文件和清单文件进行签名和验证
+pub struct Signing {
+    signing_key: Option<SigningKey>,
+    verifying_key: Option<VerifyingKey>,
+}
+
+impl Signing {
+    /// key_file: 保存私钥(hex格式的32字节种子)的文件，为空时不签名<br/>
+    /// key_env: 保存私钥的环境变量名称，优先于key_file<br/>
+    /// verify_key: hex格式的公钥，为空时从私钥推导
+    pub fn new(key_file: &str, key_env: &str, verify_key: &str) -> AppResult<Signing> {
+        let secret = match env::var(key_env) {
+            Ok(secret) if !key_env.is_empty() && !secret.trim().is_empty() => Some(secret),
+            _ if !key_file.is_empty() => Some(File::new(key_file).read()?),
+            _ => None,
+        };
+
+        let signing_key = match secret {
+            Some(secret) => {
+                let seed = decode::<32>(secret.trim(), "signing key")?;
+                Some(SigningKey::from_bytes(&seed))
+            },
+            None => None,
+        };
+
+        let verifying_key = if !verify_key.is_empty() {
+            let bytes = decode::<32>(verify_key.trim(), "verify-key")?;
+            Some(VerifyingKey::from_bytes(&bytes).map_err(|e| Error::new(ErrorKind::InvalidInput, format!("invalid verify-key: {}", e)))?)
+        } else {
+            signing_key.as_ref().map(|k| k.verifying_key())
+        };
+
+        Ok(Signing { signing_key, verifying_key })
+    }
+
+    /// 是否配置了可以用来验证签名的公钥(直接配置或者从私钥推导)
+    pub fn can_verify(&self) -> bool {
+        self.verifying_key.is_some()
+    }
+
+    /// 一个文件对应的签名文件
+    pub fn signature_file(file: &File) -> File {
+        File::new(&(file.path() + SIGNATURE_SUFFIX))
+    }
+
+    /// 对文件进行签名，并把hex格式的签名写到旁边的.sig文件里，返回签名文件。没有配置私钥时什么也不做
+    pub fn sign_file(&self, file: &File) -> AppResult<Option<File>> {
+        let key = match &self.signing_key {
+            Some(key) => key,
+            None => return Ok(None),
+        };
+
+        let signature = key.sign(&fs::read(file.get_raw())?);
+        let signature_file = Signing::signature_file(file);
+        if signature_file.exists() {
+            signature_file.rm()?;
+        }
+        signature_file.write(&hex::encode(signature.to_bytes()))?;
+
+        Ok(Some(signature_file))
+    }
+
+    /// 使用旁边的.sig文件验证一个文件，没有签名或者签名不正确时返回错误
+    pub fn verify_file(&self, file: &File) -> AppResult<()> {
+        let key = self.verifying_key.as_ref()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidInput, "no verify-key or signing key is configured"))?;
+
+        let signature_file = Signing::signature_file(file);
+        if !signature_file.exists() {
+            return Err(Box::new(Error::new(ErrorKind::NotFound, format!("the file is not signed: {}", file.path()))));
+        }
+
+        let signature = Signature::from_bytes(&decode::<64>(signature_file.read()?.trim(), "signature")?);
+        key.verify(&fs::read(file.get_raw())?, &signature)
+            .map_err(|_e| Error::new(ErrorKind::InvalidData, format!("bad signature: {}", file.path())))?;
+
+        Ok(())
+    }
+}
+
+/// 解码固定长度的hex字符串
+fn decode<const N: usize>(text: &str, what: &str) -> AppResult<[u8; N]> {
+    let bytes = hex::decode(text).map_err(|e| Error::new(ErrorKind::InvalidInput, format!("invalid {}: {}", what, e)))?;
+    let bytes: [u8; N] = bytes.try_into()
+        .map_err(|_e| Error::new(ErrorKind::InvalidInput, format!("invalid {}: expected {} bytes", what, N)))?;
+
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TempDir;
+
+    const SEED: &str = "9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60";
+    const PUBLIC_KEY: &str = "d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a";
+
+    fn temp_file(temp: &TempDir, name: &str, contents: &str) -> File {
+        let file = temp.file(name);
+        file.write_atomically(contents).unwrap();
+        file
+    }
+
+    #[test]
+    fn sign_and_verify() {
+        let temp = TempDir::new("signing");
+        let key_file = temp_file(&temp, "key", SEED);
+        let file = temp_file(&temp, "state.json", "[]");
+        let signing = Signing::new(&key_file.path(), "", "").unwrap();
+
+        let signature_file = signing.sign_file(&file).unwrap().unwrap();
+        assert_eq!(signature_file.path(), file.path() + SIGNATURE_SUFFIX);
+        signing.verify_file(&file).unwrap();
+
+        // 只配置了公钥时也能验证
+        Signing::new("", "", PUBLIC_KEY).unwrap().verify_file(&file).unwrap();
+    }
+
+    #[test]
+    fn tampered_file_is_rejected() {
+        let temp = TempDir::new("signing");
+        let key_file = temp_file(&temp, "key", SEED);
+        let file = temp_file(&temp, "state.json", "[]");
+        let signing = Signing::new(&key_file.path(), "", "").unwrap();
+        signing.sign_file(&file).unwrap();
+
+        file.write_atomically("[{\"name\":\"evil\"}]").unwrap();
+        assert!(signing.verify_file(&file).is_err());
+
+        // 签名被篡改
+        signing.sign_file(&file).unwrap();
+        let signature_file = Signing::signature_file(&file);
+        let mut signature = signature_file.read().unwrap();
+        signature.replace_range(0..2, if signature.starts_with("00") { "01" } else { "00" });
+        signature_file.write_atomically(signature).unwrap();
+        assert!(signing.verify_file(&file).is_err());
+
+        // 使用其它的公钥
+        signing.sign_file(&file).unwrap();
+        let other = Signing::new("", "", "3d4017c3e843895a92b70aa74d1b7ebc9c982ccf2ec4968cc0cd55f12af4660c").unwrap();
+        assert!(other.verify_file(&file).is_err());
+    }
+
+    #[test]
+    fn unsigned_file_is_rejected() {
+        let temp = TempDir::new("signing");
+        let file = temp_file(&temp, "state.json", "[]");
+        let signing = Signing::new("", "", PUBLIC_KEY).unwrap();
+
+        assert!(signing.verify_file(&file).is_err());
+        assert!(signing.sign_file(&file).unwrap().is_none());
+        assert!(signing.can_verify());
+        assert!(!Signing::new("", "", "").unwrap().can_verify());
+    }
+
+    #[test]
+    fn invalid_keys_are_rejected() {
+        assert!(Signing::new("", "", "not hex").is_err());
+        assert!(Signing::new("", "", &PUBLIC_KEY[2..]).is_err());
+    }
+}
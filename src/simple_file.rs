@@ -6,16 +6,27 @@ pub struct FileData {
     pub length: u64,
     pub sha1: String,
     pub modified: u64,
+    /// 纳秒精度的修改时间，旧版本的状态文件里没有记录，此时为None
+    pub modified_ns: Option<u64>,
+    /// unix权限，未记录时为None
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
 }
 
 pub struct DirData {
     pub files: Vec<SimpleFile>,
 }
 
+pub struct LinkData {
+    pub target: String,
+}
+
 pub struct SimpleFile {
     pub name: String,
     file_data: Option<FileData>,
     dir_data: Option<DirData>,
+    link_data: Option<LinkData>,
 }
 
 impl SimpleFile {
@@ -26,8 +37,13 @@ impl SimpleFile {
                 length,
                 sha1: sha1.to_owned(), 
                 modified,
+                modified_ns: None,
+                mode: None,
+                uid: None,
+                gid: None,
             }),
-            dir_data: None
+            dir_data: None,
+            link_data: None,
         }
     }
 
@@ -37,7 +53,19 @@ impl SimpleFile {
             file_data: None,
             dir_data: Some(DirData {
                 files
-            })
+            }),
+            link_data: None,
+        }
+    }
+
+    pub fn new_symlink(name: &str, target: &str) -> SimpleFile {
+        SimpleFile {
+            name: name.to_owned(),
+            file_data: None,
+            dir_data: None,
+            link_data: Some(LinkData {
+                target: target.to_owned()
+            }),
         }
     }
 
@@ -48,23 +76,9 @@ impl SimpleFile {
         } else {
             file.sha1()?
         };
-        Ok(SimpleFile::new_file(file.name(), file.length()?, &hash, file.modified()?))
-    }
-
-    pub fn from_real_directory(dir: &File, extra: Option<(&HashCache, &File, bool)>) -> Result<SimpleFile> {
-        let files = dir.files()?
-            .filter_map(|v| v.ok())
-            .filter_map(|v: File| -> Option<SimpleFile> {
-                if v.is_dir() {
-                    Some(SimpleFile::from_real_directory(&v, extra).map_or_else(|_e| None, |v| Some(v))?)
-                } else if v.is_file() {
-                    Some(SimpleFile::from_real_file(&v, extra).map_or_else(|_e| None, |v| Some(v))?)
-                } else {
-                    None
-                }
-            }).collect::<Vec<SimpleFile>>();
-
-        Ok(SimpleFile::new_directory(dir.name(), files))
+        let mut simple_file = SimpleFile::new_file(file.name(), file.length()?, &hash, file.modified()?);
+        simple_file.as_file_mut().unwrap().modified_ns = Some(file.modified_nanos()?);
+        Ok(simple_file)
     }
 
     pub fn is_file(&self) -> bool {
@@ -75,6 +89,10 @@ impl SimpleFile {
         self.dir_data.is_some()
     }
 
+    pub fn is_symlink(&self) -> bool {
+        self.link_data.is_some()
+    }
+
     pub fn as_file(&self) -> Option<&FileData> {
         self.file_data.as_ref()
     }
@@ -83,6 +101,10 @@ impl SimpleFile {
         self.dir_data.as_ref()
     }
 
+    pub fn as_symlink(&self) -> Option<&LinkData> {
+        self.link_data.as_ref()
+    }
+
     pub fn as_file_mut(&mut self) -> Option<&mut FileData> {
         self.file_data.as_mut()
     }
@@ -94,14 +116,14 @@ impl SimpleFile {
 
 impl Clone for SimpleFile {
     fn clone(&self) -> Self {
-        Self { name: self.name.clone(), file_data: self.file_data.clone(), dir_data: self.dir_data.clone() }
+        Self { name: self.name.clone(), file_data: self.file_data.clone(), dir_data: self.dir_data.clone(), link_data: self.link_data.clone() }
     }
 }
 
 impl PartialEq for SimpleFile {
     fn eq(&self, other: &Self) -> bool {
         let mut result = self.name == other.name;
-        result &= self.is_file() == other.is_file() && self.is_dir() == other.is_dir();
+        result &= self.is_file() == other.is_file() && self.is_dir() == other.is_dir() && self.is_symlink() == other.is_symlink();
 
         if result && self.is_file() { 
             let lfd = self.file_data.as_ref().unwrap();
@@ -115,25 +137,54 @@ impl PartialEq for SimpleFile {
             result &= lfd == rfd;
         }
 
+        if result && self.is_symlink() { 
+            let lld = self.link_data.as_ref().unwrap();
+            let rld = other.link_data.as_ref().unwrap();
+            result &= lld.target == rld.target;
+        }
+
         result
     }
 }
 
 impl FileData {
     pub fn new(length: u64, sha1: String, modified: u64,) -> FileData {
-        FileData { length, sha1, modified }
+        FileData { length, sha1, modified, modified_ns: None, mode: None, uid: None, gid: None }
+    }
+
+    /// 判断记录的修改时间和本地文件的修改时间是否一致
+    /// 
+    /// modified_ns: 本地文件的修改时间(纳秒)<br/>
+    /// tolerance_ns: 允许的误差(纳秒)，用于修改时间精度较低的文件系统
+    pub fn modified_matches(&self, modified_ns: u64, tolerance_ns: u64) -> bool {
+        match self.modified_ns {
+            Some(recorded) => recorded.abs_diff(modified_ns) <= tolerance_ns,
+            // 旧版本的状态文件只记录了秒
+            None => {
+                let start = self.modified.saturating_mul(1_000_000_000);
+                let end = start.saturating_add(999_999_999);
+                modified_ns.saturating_add(tolerance_ns) >= start && modified_ns <= end.saturating_add(tolerance_ns)
+            },
+        }
     }
 }
 
 impl Clone for FileData {
     fn clone(&self) -> Self {
-        Self { length: self.length.clone(), sha1: self.sha1.clone(), modified: self.modified.clone() }
+        Self { length: self.length.clone(), sha1: self.sha1.clone(), modified: self.modified.clone(), modified_ns: self.modified_ns, mode: self.mode, uid: self.uid, gid: self.gid }
     }
 }
 
 impl PartialEq for FileData {
     fn eq(&self, other: &Self) -> bool {
-        self.length == other.length && self.sha1 == other.sha1 && self.modified == other.modified
+        self.length == other.length && self.sha1 == other.sha1 && self.modified == other.modified && self.modified_ns == other.modified_ns &&
+            self.mode == other.mode && self.uid == other.uid && self.gid == other.gid
+    }
+}
+
+impl Clone for LinkData {
+    fn clone(&self) -> Self {
+        Self { target: self.target.clone() }
     }
 }
 
@@ -171,9 +222,9 @@ impl DirData {
             let name = split[index];
             let reach_end = index == split.len() - 1;
 
-            let current = (&mut current_dir.files).iter_mut().filter(|f| f.name == name).next().unwrap();
+            let current = (&mut current_dir.files).iter_mut().filter(|f| f.name == name).next()?;
             if !reach_end {
-                current_dir = (current.dir_data.as_mut()).unwrap();
+                current_dir = (current.dir_data.as_mut())?;
             } else {
                 return Some(current);
             }
@@ -0,0 +1,169 @@
+use std::io::Error;
+use std::io::ErrorKind;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use crate::AppResult;
+use crate::file::File;
+
+/// 文件名里快照部分的前缀，完整的文件名为: 状态文件名.snapshot-时间戳[.序号][-标签]<br/>
+/// 同一秒里保存了多个快照时，从第二个开始加上序号，保证文件名不重复
+const SNAPSHOT_INFIX: &str = ".snapshot-";
+
+/// 保存在状态文件旁边的一份状态快照
+pub struct Snapshot {
+    pub file: File,
+    /// 保存时间(unix时间戳，秒)
+    pub time: u64,
+    /// 同一秒里保存的第几个快照，从0开始
+    pub seq: u32,
+    /// 快照的标签，没有时为空
+    pub label: String,
+}
+
+impl Snapshot {
+    /// 文件名里的时间戳部分，同一秒里有多个快照时带上序号，例如1700000000.1
+    pub fn stamp(&self) -> String {
+        if self.seq == 0 { self.time.to_string() } else { format!("{}.{}", self.time, self.seq) }
+    }
+}
+
+/// 管理一个状态文件的所有快照
+pub struct SnapshotStore {
+    state_file: File,
+}
+
+impl SnapshotStore {
+    pub fn new(state_file: &File) -> SnapshotStore {
+        SnapshotStore { state_file: state_file.to_owned() }
+    }
+
+    /// 列出所有的快照，按保存时间从旧到新排列
+    pub fn list(&self) -> AppResult<Vec<Snapshot>> {
+        let mut snapshots = Vec::<Snapshot>::new();
+
+        let dir = match self.state_file.parent()? {
+            Some(dir) if dir.is_dir() => dir,
+            _ => return Ok(snapshots),
+        };
+        let prefix = self.state_file.name().to_owned() + SNAPSHOT_INFIX;
+
+        for file in dir.files()? {
+            let file = file?;
+            let rest = match file.name().strip_prefix(&prefix) {
+                Some(rest) if file.is_file() => rest.to_owned(),
+                _ => continue,
+            };
+            let (stamp, label) = rest.split_once('-').unwrap_or((&rest, ""));
+            let (time, seq) = stamp.split_once('.').unwrap_or((stamp, "0"));
+            if let (Ok(time), Ok(seq)) = (time.parse::<u64>(), seq.parse::<u32>()) {
+                snapshots.push(Snapshot { time, seq, label: label.to_owned(), file });
+            }
+        }
+
+        snapshots.sort_by_key(|s| (s.time, s.seq));
+        Ok(snapshots)
+    }
+
+    /// 按照标签或者时间戳查找快照，有多个时返回最新的那一个
+    pub fn find(&self, label: &str) -> AppResult<Option<Snapshot>> {
+        Ok(self.list()?.into_iter().rev().find(|s| s.label == label || s.time.to_string() == label || s.stamp() == label))
+    }
+
+    /// 保存一份新的快照
+    ///
+    /// contents: 状态文件的内容<br/>
+    /// label: 快照的标签，可以为空
+    pub fn take(&self, contents: &[u8], label: &str) -> AppResult<Snapshot> {
+        if label.contains('/') || label.contains('\\') {
+            return Err(Box::new(Error::new(ErrorKind::InvalidInput, format!("the label of the snapshot must not contain path separators: {}", label))));
+        }
+
+        let time = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let seq = self.list()?.iter()
+            .filter(|s| s.time == time)
+            .map(|s| s.seq + 1)
+            .max()
+            .unwrap_or(0);
+        let mut snapshot = Snapshot { file: self.state_file.clone(), time, seq, label: label.to_owned() };
+        let name = if label.is_empty() {
+            format!("{}{}{}", self.state_file.name(), SNAPSHOT_INFIX, snapshot.stamp())
+        } else {
+            format!("{}{}{}-{}", self.state_file.name(), SNAPSHOT_INFIX, snapshot.stamp(), label)
+        };
+
+        let dir = self.state_file.parent()?.unwrap();
+        dir.mkdirs()?;
+        snapshot.file = dir.append(&name)?;
+        snapshot.file.write_atomically(contents)?;
+
+        Ok(snapshot)
+    }
+
+    /// 只保留最新的keep个快照，返回被删除的快照数量。keep为0时保留所有的快照
+    pub fn prune(&self, keep: usize) -> AppResult<usize> {
+        let snapshots = self.list()?;
+        if keep == 0 || snapshots.len() <= keep {
+            return Ok(0);
+        }
+
+        let expired = snapshots.len() - keep;
+        for s in &snapshots[..expired] {
+            s.file.rm()?;
+        }
+
+        Ok(expired)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TempDir;
+
+    #[test]
+    fn snapshots_in_the_same_second_are_kept_apart() {
+        let temp = TempDir::new("snapshot");
+        let store = SnapshotStore::new(&temp.file("state.json"));
+
+        let mut taken = Vec::new();
+        for i in 0..4 {
+            taken.push(store.take(format!("[{}]", i).as_bytes(), if i == 1 { "v1" } else { "" }).unwrap());
+        }
+        // 时间跨过了一秒时序号会重新从0开始，只检查文件名都不一样
+        let mut names: Vec<String> = taken.iter().map(|s| s.file.name().to_owned()).collect();
+        names.dedup();
+        assert_eq!(names.len(), 4);
+
+        let listed = store.list().unwrap();
+        assert_eq!(listed.len(), 4);
+        for (i, s) in listed.iter().enumerate() {
+            assert_eq!(s.file.read().unwrap(), format!("[{}]", i));
+        }
+        assert_eq!(store.find("v1").unwrap().unwrap().file.read().unwrap(), "[1]");
+        assert_eq!(store.find(&taken[2].stamp()).unwrap().unwrap().file.read().unwrap(), "[2]");
+
+        assert_eq!(store.prune(2).unwrap(), 2);
+        let listed = store.list().unwrap();
+        assert_eq!(listed.len(), 2);
+        assert_eq!(listed[0].file.read().unwrap(), "[2]");
+        assert_eq!(listed[1].file.read().unwrap(), "[3]");
+        assert_eq!(store.prune(0).unwrap(), 0);
+    }
+
+    #[test]
+    fn old_snapshot_names_are_still_listed() {
+        let temp = TempDir::new("snapshot");
+        let store = SnapshotStore::new(&temp.file("state.json"));
+        temp.file("state.json.snapshot-100-v1.0").write_atomically("a").unwrap();
+        temp.file("state.json.snapshot-100.1").write_atomically("b").unwrap();
+        temp.file("state.json.snapshot-99").write_atomically("c").unwrap();
+        temp.file("state.json.snapshot-bad").write_atomically("d").unwrap();
+
+        let listed = store.list().unwrap();
+        let stamps: Vec<String> = listed.iter().map(|s| s.stamp()).collect();
+        assert_eq!(stamps, vec!["99", "100", "100.1"]);
+        assert_eq!(listed[1].label, "v1.0");
+        assert!(store.take(b"x", "a/b").is_err());
+    }
+}
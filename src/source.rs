@@ -0,0 +1,100 @@
+use std::io::Error;
+use std::io::ErrorKind;
+
+use crate::AppResult;
+use crate::app_config::SourceConfig;
+use crate::file::File;
+use crate::file_selector::FileSelector;
+use crate::hash_cache::HashCache;
+
+/// 一个源目录，以及它在状态里所对应的目录(前缀)
+pub struct Source {
+    pub dir: File,
+    pub prefix: String,
+    pub selector: FileSelector,
+    pub hash_cache: HashCache,
+}
+
+impl Source {
+    pub fn new(config: &SourceConfig) -> AppResult<Source> {
+        let dir = &config.dir;
+        let dir = if dir.ends_with("/") { &dir[0..dir.len() - 1] } else { &dir[..] }.to_owned();
+        let dir = File::new(&dir);
+        if !dir.is_dir() {
+            return Err(Box::new(Error::new(ErrorKind::NotFound, format!("the source-directory is not a dir: {}", config.dir))))
+        }
+
+        let hash_cache = HashCache::new(&dir);
+        let selector = FileSelector::new(&dir, config)?;
+
+        Ok(Source { dir, prefix: config.prefix.to_owned(), selector, hash_cache })
+    }
+
+    /// 检查多个源目录的前缀，每个源目录都必须对应状态里互不重叠的目录
+    pub fn check_prefixes(sources: &[Source]) -> AppResult<()> {
+        for (i, a) in sources.iter().enumerate() {
+            if a.prefix.is_empty() && sources.len() > 1 {
+                return Err(Box::new(Error::new(ErrorKind::InvalidInput, format!("the prefix of the source must be not empty when using multiple sources: {}", a.dir.path()))))
+            }
+
+            for b in &sources[i + 1..] {
+                let overlapped = a.prefix == b.prefix ||
+                    a.prefix.starts_with(&(b.prefix.to_owned() + "/")) ||
+                    b.prefix.starts_with(&(a.prefix.to_owned() + "/"));
+
+                if overlapped {
+                    return Err(Box::new(Error::new(ErrorKind::InvalidInput, format!("the prefixes of the sources overlap: '{}' and '{}'", a.prefix, b.prefix))))
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// 判断源目录里的一个相对路径是否需要同步
+    pub fn filter(&self, local_path: &str, is_dir: bool) -> std::io::Result<bool> {
+        self.selector.is_selected(local_path, is_dir)
+    }
+
+    /// 将源目录里的相对路径转换为状态里的路径(加上前缀)
+    pub fn to_state_path(&self, local_path: &str) -> String {
+        if self.prefix.is_empty() {
+            local_path.to_owned()
+        } else if local_path.is_empty() {
+            self.prefix.to_owned()
+        } else {
+            self.prefix.to_owned() + "/" + local_path
+        }
+    }
+
+    /// 将状态里的路径转换为源目录里的相对路径(去掉前缀)，不属于这个源目录时返回None
+    pub fn to_local_path(&self, state_path: &str) -> Option<String> {
+        if self.prefix.is_empty() {
+            return Some(state_path.to_owned());
+        }
+
+        if state_path == self.prefix {
+            return Some("".to_owned());
+        }
+
+        state_path.strip_prefix(&(self.prefix.to_owned() + "/")).map(|p| p.to_owned())
+    }
+
+    /// 获取一个本地文件在源目录里的相对路径，不在源目录里时返回None
+    pub fn relativize(&self, file: &File) -> Option<String> {
+        if file.path() == self.dir.path() {
+            return Some("".to_owned());
+        }
+
+        if !file.path().starts_with(&(self.dir.path() + "/")) {
+            return None;
+        }
+
+        Some(file.relativized_by(&self.dir))
+    }
+
+    /// 在多个源目录中找到状态里的路径所属的那一个，返回源目录和它在源目录里的相对路径
+    pub fn locate<'a>(sources: &'a [Source], state_path: &str) -> Option<(&'a Source, String)> {
+        sources.iter().find_map(|s| s.to_local_path(state_path).map(|p| (s, p)))
+    }
+}
\ No newline at end of file
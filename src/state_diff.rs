@@ -0,0 +1,159 @@
+use std::collections::HashSet;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Result;
+
+use json::JsonValue;
+use json::object;
+
+use crate::differences::Differences;
+use crate::file_state::State;
+
+/// state diff的输出格式
+pub enum DiffFormat {
+    Text,
+    Json,
+}
+
+impl DiffFormat {
+    pub fn parse(text: &str) -> Result<DiffFormat> {
+        match text {
+            "text" => Ok(DiffFormat::Text),
+            "json" => Ok(DiffFormat::Json),
+            _ => Err(Error::new(ErrorKind::InvalidInput, format!("unknown format of state diff: {}", text))),
+        }
+    }
+}
+
+/// 两个状态之间的差异报告，分为新增、删除和修改三类
+pub struct StateDiff<'a> {
+    old: &'a State,
+    new: &'a State,
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub changed: Vec<String>,
+}
+
+impl StateDiff<'_> {
+    pub fn new<'a>(old: &'a State, new: &'a State) -> StateDiff<'a> {
+        let differences = Differences::between(&old.files, &new.files);
+
+        // 状态里可能有几十万个文件，用HashSet来判断路径是否同时出现在两边
+        let removing = differences.old_files.iter().chain(differences.old_folders.iter()).collect::<HashSet<&String>>();
+        let adding = differences.new_folders.iter()
+            .chain(differences.new_files.iter())
+            .chain(differences.new_symlinks.iter().map(|l| &l.0))
+            .collect::<HashSet<&String>>();
+
+        let mut added = adding.iter().filter(|p| !removing.contains(*p)).map(|p| p.to_string()).collect::<Vec<String>>();
+        let mut removed = removing.iter().filter(|p| !adding.contains(*p)).map(|p| p.to_string()).collect::<Vec<String>>();
+        // 同时被删除和新增的路径是内容或者类型发生了变化，只有权限变化的文件也算作修改
+        let mut changed = removing.iter().filter(|p| adding.contains(*p)).map(|p| p.to_string())
+            .chain(differences.changed_metadata.iter().cloned())
+            .collect::<Vec<String>>();
+
+        added.sort();
+        removed.sort();
+        changed.sort();
+
+        StateDiff { old, new, added, removed, changed }
+    }
+
+    pub fn has_differences(&self) -> bool {
+        !self.added.is_empty() || !self.removed.is_empty() || !self.changed.is_empty()
+    }
+
+    pub fn render(&self, format: &DiffFormat) -> String {
+        match format {
+            DiffFormat::Text => self.render_text(),
+            DiffFormat::Json => self.render_json().pretty(2),
+        }
+    }
+
+    fn render_text(&self) -> String {
+        let mut lines = Vec::<String>::new();
+
+        for path in &self.added {
+            lines.push(format!("+ {}{}", path, StateDiff::describe(self.new, path)));
+        }
+
+        for path in &self.removed {
+            lines.push(format!("- {}{}", path, StateDiff::describe(self.old, path)));
+        }
+
+        for path in &self.changed {
+            let old = StateDiff::entry(self.old, path);
+            let new = StateDiff::entry(self.new, path);
+
+            let detail = if old["type"] != new["type"] {
+                format!("{} -> {}", old["type"], new["type"])
+            } else if let (Some(ol), Some(nl)) = (old["link"].as_str(), new["link"].as_str()) {
+                format!("link {} -> {}", ol, nl)
+            } else if old["hash"] == new["hash"] {
+                format!("mode {} -> {}", old["mode"], new["mode"])
+            } else {
+                let (ol, nl) = (old["length"].as_u64().unwrap_or(0), new["length"].as_u64().unwrap_or(0));
+                format!("length {} -> {} ({:+}), hash {} -> {}", ol, nl, nl as i64 - ol as i64, old["hash"], new["hash"])
+            };
+            lines.push(format!("~ {}  {}", path, detail));
+        }
+
+        lines.push(format!("新增: {}, 删除: {}, 修改: {}", self.added.len(), self.removed.len(), self.changed.len()));
+        lines.join("\n")
+    }
+
+    fn render_json(&self) -> JsonValue {
+        let mut added = JsonValue::new_array();
+        for path in &self.added {
+            added.push(StateDiff::entry(self.new, path)).unwrap();
+        }
+
+        let mut removed = JsonValue::new_array();
+        for path in &self.removed {
+            removed.push(StateDiff::entry(self.old, path)).unwrap();
+        }
+
+        let mut changed = JsonValue::new_array();
+        for path in &self.changed {
+            let old = StateDiff::entry(self.old, path);
+            let new = StateDiff::entry(self.new, path);
+            let mut item = object! { path: path.to_owned(), old: old.clone(), new: new.clone() };
+            if let (Some(ol), Some(nl)) = (old["length"].as_u64(), new["length"].as_u64()) {
+                item["length-delta"] = (nl as i64 - ol as i64).into();
+            }
+            changed.push(item).unwrap();
+        }
+
+        object! { added: added, removed: removed, changed: changed }
+    }
+
+    /// 状态里一个文件/目录/符号链接的信息
+    fn entry(state: &State, path: &str) -> JsonValue {
+        let file = match state.files.get_file(path) {
+            Some(file) => file,
+            None => return object! { path: path },
+        };
+
+        if let Some(f) = file.as_file() {
+            let mut entry = object! { path: path, type: "file", length: f.length, hash: f.sha1.to_owned() };
+            if let Some(mode) = f.mode {
+                entry["mode"] = format!("{:o}", mode).into();
+            }
+            entry
+        } else if let Some(l) = file.as_symlink() {
+            object! { path: path, type: "symlink", link: l.target.to_owned() }
+        } else {
+            object! { path: path, type: "dir" }
+        }
+    }
+
+    /// 文本格式里跟在路径后面的简短描述
+    fn describe(state: &State, path: &str) -> String {
+        let entry = StateDiff::entry(state, path);
+        match entry["type"].as_str() {
+            Some("file") => format!("  length {}, hash {}", entry["length"], entry["hash"]),
+            Some("symlink") => format!(" -> {}", entry["link"]),
+            _ => "/".to_owned(),
+        }
+    }
+}
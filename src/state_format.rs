@@ -0,0 +1,317 @@
+use std::fs;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Read;
+use std::io::Result;
+use std::io::Write;
+
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+
+use crate::AppResult;
+use crate::file::File;
+use crate::file_state::State;
+use crate::simple_file::DirData;
+use crate::simple_file::SimpleFile;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const BINARY_MAGIC: &[u8] = b"IUST";
+const BINARY_VERSION: u8 = 1;
+
+const ENTRY_FILE: u8 = 0;
+const ENTRY_DIR: u8 = 1;
+const ENTRY_SYMLINK: u8 = 2;
+
+const HAS_MODIFIED_NS: u8 = 1;
+const HAS_MODE: u8 = 2;
+const HAS_UID: u8 = 4;
+const HAS_GID: u8 = 8;
+
+/// 状态文件的存储格式
+pub enum StateFormat {
+    Json,
+    JsonGz,
+    JsonZst,
+    /// 紧凑的二进制格式，读取时直接解析成DirData，不经过JsonValue
+    Binary,
+}
+
+impl StateFormat {
+    pub fn parse(text: &str) -> Result<StateFormat> {
+        match text {
+            "json" => Ok(StateFormat::Json),
+            "json.gz" => Ok(StateFormat::JsonGz),
+            "json.zst" => Ok(StateFormat::JsonZst),
+            "binary" => Ok(StateFormat::Binary),
+            _ => Err(Error::new(ErrorKind::InvalidInput, format!("unknown value of state-format: {}", text))),
+        }
+    }
+
+    /// 决定写入状态文件时使用的格式，没有配置时根据状态文件的扩展名决定
+    pub fn for_file(config: &str, state_file: &str) -> Result<StateFormat> {
+        if !config.is_empty() {
+            return StateFormat::parse(config);
+        }
+
+        Ok(if state_file.ends_with(".gz") {
+            StateFormat::JsonGz
+        } else if state_file.ends_with(".zst") {
+            StateFormat::JsonZst
+        } else if state_file.ends_with(".bin") {
+            StateFormat::Binary
+        } else {
+            StateFormat::Json
+        })
+    }
+
+    /// 把状态编码成这种格式的内容
+    ///
+    /// indent: json格式的缩进，为0时不缩进
+    pub fn encode(&self, state: &State, indent: u16) -> Result<Vec<u8>> {
+        let json = || {
+            let json = state.to_json_array();
+            if indent > 0 { json.pretty(indent) } else { json.dump() }
+        };
+
+        match self {
+            StateFormat::Json => Ok(json().into_bytes()),
+            StateFormat::JsonGz => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(json().as_bytes())?;
+                encoder.finish()
+            },
+            StateFormat::JsonZst => zstd::encode_all(json().as_bytes(), 0),
+            StateFormat::Binary => {
+                let mut buf = BINARY_MAGIC.to_vec();
+                buf.push(BINARY_VERSION);
+                write_dir(&mut buf, &state.files)?;
+                Ok(buf)
+            },
+        }
+    }
+
+    /// 读取一个任意格式的状态文件，格式根据文件开头的内容自动识别
+    pub fn read(file: &File) -> AppResult<State> {
+        let mut reader = BufReader::new(fs::File::open(file.get_raw())?);
+        let head = reader.fill_buf()?;
+
+        let state = if head.starts_with(BINARY_MAGIC) {
+            let mut header = [0u8; 5];
+            reader.read_exact(&mut header)?;
+            if header[4] != BINARY_VERSION {
+                return Err(Box::new(Error::new(ErrorKind::InvalidData, format!("unsupported version of the binary state file: {}", header[4]))));
+            }
+            State { files: read_dir(&mut reader)? }
+        } else {
+            let mut text = String::new();
+            if head.starts_with(GZIP_MAGIC) {
+                GzDecoder::new(reader).read_to_string(&mut text)?;
+            } else if head.starts_with(ZSTD_MAGIC) {
+                zstd::Decoder::with_buffer(reader)?.read_to_string(&mut text)?;
+            } else {
+                reader.read_to_string(&mut text)?;
+            }
+            State::from_json_array(&json::parse(&text)?)
+        };
+
+        Ok(state)
+    }
+}
+
+fn write_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn write_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    write_varint(buf, bytes.len() as u64);
+    buf.extend_from_slice(bytes);
+}
+
+fn write_dir(buf: &mut Vec<u8>, dir: &DirData) -> Result<()> {
+    write_varint(buf, dir.files.len() as u64);
+
+    for f in &dir.files {
+        if let Some(data) = f.as_file() {
+            buf.push(ENTRY_FILE);
+            write_bytes(buf, f.name.as_bytes());
+
+            let mut flags = 0;
+            flags |= if data.modified_ns.is_some() { HAS_MODIFIED_NS } else { 0 };
+            flags |= if data.mode.is_some() { HAS_MODE } else { 0 };
+            flags |= if data.uid.is_some() { HAS_UID } else { 0 };
+            flags |= if data.gid.is_some() { HAS_GID } else { 0 };
+            buf.push(flags);
+
+            write_varint(buf, data.length);
+            write_varint(buf, data.modified);
+            for value in [data.modified_ns, data.mode.map(|v| v as u64), data.uid.map(|v| v as u64), data.gid.map(|v| v as u64)].into_iter().flatten() {
+                write_varint(buf, value);
+            }
+            // hash以原始字节保存，比hex字符串小一半
+            let hash = hex::decode(&data.sha1).map_err(|_e| Error::new(ErrorKind::InvalidData, format!("the hash of the file is not a hex string: {}", f.name)))?;
+            write_bytes(buf, &hash);
+        } else if let Some(dir) = f.as_dir() {
+            buf.push(ENTRY_DIR);
+            write_bytes(buf, f.name.as_bytes());
+            write_dir(buf, dir)?;
+        } else if let Some(link) = f.as_symlink() {
+            buf.push(ENTRY_SYMLINK);
+            write_bytes(buf, f.name.as_bytes());
+            write_bytes(buf, link.target.as_bytes());
+        }
+    }
+
+    Ok(())
+}
+
+fn read_u8(reader: &mut impl Read) -> Result<u8> {
+    let mut byte = [0u8; 1];
+    reader.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+fn read_varint(reader: &mut impl Read) -> Result<u64> {
+    let mut value = 0u64;
+    let mut shift = 0;
+    loop {
+        let byte = read_u8(reader)?;
+        if shift >= 64 {
+            return Err(Error::new(ErrorKind::InvalidData, "the varint in the binary state file is too long"));
+        }
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+        shift += 7;
+    }
+}
+
+fn read_bytes(reader: &mut impl Read) -> Result<Vec<u8>> {
+    let len = read_varint(reader)? as usize;
+    let mut bytes = Vec::new();
+    reader.by_ref().take(len as u64).read_to_end(&mut bytes)?;
+    if bytes.len() != len {
+        return Err(Error::new(ErrorKind::UnexpectedEof, "the binary state file is truncated"));
+    }
+    Ok(bytes)
+}
+
+fn read_string(reader: &mut impl Read) -> Result<String> {
+    String::from_utf8(read_bytes(reader)?).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+}
+
+fn read_dir(reader: &mut impl Read) -> Result<DirData> {
+    let count = read_varint(reader)?;
+    let mut files = Vec::<SimpleFile>::new();
+
+    for _ in 0..count {
+        let kind = read_u8(reader)?;
+        let name = read_string(reader)?;
+
+        match kind {
+            ENTRY_FILE => {
+                let flags = read_u8(reader)?;
+                let length = read_varint(reader)?;
+                let modified = read_varint(reader)?;
+                let mut optional = |flag: u8| -> Result<Option<u64>> {
+                    if flags & flag != 0 { read_varint(reader).map(Some) } else { Ok(None) }
+                };
+                let modified_ns = optional(HAS_MODIFIED_NS)?;
+                let mode = optional(HAS_MODE)?.map(|v| v as u32);
+                let uid = optional(HAS_UID)?.map(|v| v as u32);
+                let gid = optional(HAS_GID)?.map(|v| v as u32);
+                let hash = hex::encode(read_bytes(reader)?);
+
+                let mut file = SimpleFile::new_file(&name, length, &hash, modified);
+                let data = file.as_file_mut().unwrap();
+                data.modified_ns = modified_ns;
+                data.mode = mode;
+                data.uid = uid;
+                data.gid = gid;
+                files.push(file);
+            },
+            ENTRY_DIR => files.push(SimpleFile::new_directory(&name, read_dir(reader)?.files)),
+            ENTRY_SYMLINK => files.push(SimpleFile::new_symlink(&name, &read_string(reader)?)),
+            _ => return Err(Error::new(ErrorKind::InvalidData, format!("unknown entry type in the binary state file: {}", kind))),
+        }
+    }
+
+    Ok(DirData::new(files))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::TempDir;
+
+    fn sample_state() -> State {
+        let mut tracked = SimpleFile::new_file("tracked.txt", 12, "da39a3ee5e6b4b0d3255bfef95601890afd80709", 1700000000);
+        let data = tracked.as_file_mut().unwrap();
+        data.modified_ns = Some(1700000000123456789);
+        data.mode = Some(0o100755);
+        data.uid = Some(1000);
+        data.gid = Some(u32::MAX);
+
+        let plain = SimpleFile::new_file("plain.bin", 0, "0123456789abcdef0123456789abcdef01234567", 0);
+        let link = SimpleFile::new_symlink("link", "../tracked.txt");
+        let empty = SimpleFile::new_directory("empty", Vec::new());
+        let dir = SimpleFile::new_directory("dir", vec![plain, link, empty]);
+
+        State { files: DirData::new(vec![tracked, dir]) }
+    }
+
+    /// 写到临时文件里再读回来
+    fn round_trip(format: StateFormat, name: &str) -> State {
+        let temp = TempDir::new("state-format");
+        let file = temp.file(name);
+        fs::write(file.get_raw(), format.encode(&sample_state(), 0).unwrap()).unwrap();
+        StateFormat::read(&file).unwrap()
+    }
+
+    #[test]
+    fn binary_round_trip() {
+        let state = round_trip(StateFormat::Binary, "state.bin");
+        assert_eq!(state.to_json_array(), sample_state().to_json_array());
+
+        let data = state.files.get_file("tracked.txt").unwrap().as_file().unwrap();
+        assert_eq!(data.modified_ns, Some(1700000000123456789));
+        assert_eq!(data.mode, Some(0o100755));
+        assert_eq!(data.uid, Some(1000));
+        assert_eq!(data.gid, Some(u32::MAX));
+
+        let data = state.files.get_file("dir/plain.bin").unwrap().as_file().unwrap();
+        assert_eq!((data.modified_ns, data.mode, data.uid, data.gid), (None, None, None, None));
+        assert_eq!(state.files.get_file("dir/link").unwrap().as_symlink().unwrap().target, "../tracked.txt");
+        assert!(state.files.get_file("dir/empty").unwrap().is_dir());
+    }
+
+    #[test]
+    fn compressed_json_round_trip() {
+        assert_eq!(round_trip(StateFormat::JsonGz, "state.json.gz").to_json_array(), sample_state().to_json_array());
+        assert_eq!(round_trip(StateFormat::JsonZst, "state.json.zst").to_json_array(), sample_state().to_json_array());
+        assert_eq!(round_trip(StateFormat::Json, "state.json").to_json_array(), sample_state().to_json_array());
+    }
+
+    #[test]
+    fn truncated_binary_is_rejected() {
+        let mut bytes = StateFormat::Binary.encode(&sample_state(), 0).unwrap();
+        bytes.truncate(bytes.len() - 3);
+        assert!(read_dir(&mut &bytes[5..]).is_err());
+    }
+
+    #[test]
+    fn format_for_file() {
+        assert!(matches!(StateFormat::for_file("", "state.json.zst").unwrap(), StateFormat::JsonZst));
+        assert!(matches!(StateFormat::for_file("", "state.bin").unwrap(), StateFormat::Binary));
+        assert!(matches!(StateFormat::for_file("json.gz", "state.bin").unwrap(), StateFormat::JsonGz));
+        assert!(StateFormat::for_file("xml", "state.json").is_err());
+    }
+}
@@ -0,0 +1,357 @@
+use std::fs;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::process;
+use std::sync::mpsc;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::mpsc::Sender;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use json::JsonValue;
+use json::object;
+
+use crate::AppResult;
+use crate::file::File;
+
+/// 放在本地状态文件旁边的锁文件，记录持有者的PID和主机名，防止多个进程同时修改同一个状态
+pub struct StateLock {
+    file: File,
+    /// 持有者，格式为: pid@主机名
+    pub owner: String,
+}
+
+/// 已经获取到的锁，持有期间会定期刷新锁文件里的时间，被drop时(包括panic的时候)自动释放
+pub struct StateLockGuard {
+    file: File,
+    owner: String,
+    /// 通知刷新线程退出，被drop时刷新线程也会退出
+    stop: Option<Sender<()>>,
+    heartbeat: Option<JoinHandle<()>>,
+    released: bool,
+}
+
+impl StateLock {
+    pub fn new(file: File) -> StateLock {
+        StateLock { file, owner: StateLock::current_owner() }
+    }
+
+    /// 当前进程作为锁的持有者时的名称
+    pub fn current_owner() -> String {
+        format!("{}@{}", process::id(), gethostname::gethostname().to_string_lossy())
+    }
+
+    /// 获取锁，锁已经被别人持有时返回错误
+    ///
+    /// stale_timeout: 超过这个时间(秒)没有刷新过的锁被认为已经过期，会被自动清理掉，为0时锁永不过期
+    pub fn acquire(&self, stale_timeout: u64) -> AppResult<StateLockGuard> {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+
+        if self.file.exists() {
+            let lock = read_lock(&self.file)?;
+            // 无法解析的锁(比如旧版本创建了文件但是还没有写入内容)按照文件的修改时间判断是否过期
+            let time = lock["time"].as_u64().unwrap_or_else(|| self.file.modified().unwrap_or(now));
+            let age = now.saturating_sub(time);
+
+            if stale_timeout > 0 && age > stale_timeout {
+                println!("清理过期的锁: {} (持有者: {}, {}秒之前)", self.file.path(), lock["owner"].as_str().unwrap_or("unknown"), age);
+                self.remove_stale(&lock)?;
+            } else {
+                return Err(locked_error(&self.file, &lock, age));
+            }
+        }
+
+        if let Some(parent) = self.file.parent()? {
+            parent.mkdirs()?;
+        }
+
+        // 先把内容写到自己专用的临时文件里，再用硬链接放到锁文件的位置上，只有一个进程能链接成功，
+        // 而且锁文件出现的时候内容已经是完整的，别人不会读到空的锁文件
+        let temp = File::new(&format!("{}.new-{}", self.file.path(), process::id()));
+        fs::write(temp.get_raw(), lock_contents(&self.owner)?)?;
+        let linked = fs::hard_link(temp.get_raw(), self.file.get_raw());
+        temp.rm()?;
+
+        match linked {
+            Ok(()) => {},
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                return Err(locked_error(&self.file, &read_lock(&self.file)?, 0));
+            },
+            Err(e) => return Err(Box::new(Error::new(e.kind(), format!("failed to create the lock file: {}: {}", self.file.path(), e)))),
+        }
+
+        let mut guard = StateLockGuard { file: self.file.clone(), owner: self.owner.to_owned(), stop: None, heartbeat: None, released: false };
+
+        // 长时间运行的进程(比如watch)需要定期刷新锁的时间，不然会被别人当作过期的锁清理掉
+        if stale_timeout > 0 {
+            let (stop, stopped) = mpsc::channel::<()>();
+            let interval = Duration::from_secs((stale_timeout / 3).max(1));
+            let file = self.file.clone();
+            let owner = self.owner.to_owned();
+
+            guard.stop = Some(stop);
+            guard.heartbeat = Some(thread::spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    if let Err(e) = refresh_lock(&file, &owner) {
+                        println!("刷新锁的时间时出现错误: {}: {}", file.path(), e);
+                    }
+                }
+            }));
+        }
+
+        Ok(guard)
+    }
+
+    /// 清理一个过期的锁
+    ///
+    /// 多个进程可能同时发现同一个过期的锁，所以先把锁文件改成自己专用的名字，只有一个进程能改名成功，
+    /// 改名之后再确认一次拿到的还是那个过期的锁，避免删掉别人刚刚创建的锁
+    fn remove_stale(&self, stale: &JsonValue) -> AppResult<()> {
+        let taken = File::new(&format!("{}.stale-{}", self.file.path(), process::id()));
+
+        match fs::rename(self.file.get_raw(), taken.get_raw()) {
+            Ok(()) => {},
+            // 已经被别人清理掉了
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(Box::new(e)),
+        }
+
+        let lock = read_lock(&taken)?;
+        if lock["owner"] != stale["owner"] || lock["time"] != stale["time"] {
+            // 拿到的是别人新创建的锁，还回去(用硬链接还原，不会覆盖掉别人在这期间创建的锁)
+            let _ = fs::hard_link(taken.get_raw(), self.file.get_raw());
+            taken.rm()?;
+            return Err(locked_error(&self.file, &lock, 0));
+        }
+
+        taken.rm()?;
+        Ok(())
+    }
+
+    /// 不管持有者是谁，直接删掉锁文件
+    pub fn force_unlock(&self) -> AppResult<()> {
+        if self.file.exists() {
+            self.file.rm()?;
+        }
+
+        Ok(())
+    }
+}
+
+impl StateLockGuard {
+    /// 释放锁，锁已经被别人接管时不做任何事
+    pub fn release(mut self) -> AppResult<()> {
+        self.released = true;
+        self.release_file()
+    }
+
+    fn release_file(&mut self) -> AppResult<()> {
+        // 先停止刷新，避免释放之后又被重新写回去
+        self.stop.take();
+        if let Some(heartbeat) = self.heartbeat.take() {
+            let _ = heartbeat.join();
+        }
+
+        if !self.file.exists() {
+            return Ok(());
+        }
+
+        if read_lock(&self.file)?["owner"].as_str() == Some(&self.owner) {
+            self.file.rm()?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for StateLockGuard {
+    fn drop(&mut self) {
+        if !self.released {
+            if let Err(e) = self.release_file() {
+                println!("释放锁时出现错误: {}: {}", self.file.path(), e);
+            }
+        }
+    }
+}
+
+fn read_lock(file: &File) -> AppResult<JsonValue> {
+    Ok(json::parse(&file.read()?).unwrap_or(JsonValue::Null))
+}
+
+fn lock_contents(owner: &str) -> AppResult<String> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    Ok(object! { owner: owner, pid: process::id(), time: now }.pretty(2))
+}
+
+/// 更新锁文件里的时间，锁已经不属于自己时不做任何事
+fn refresh_lock(file: &File, owner: &str) -> AppResult<()> {
+    if file.exists() && read_lock(file)?["owner"].as_str() == Some(owner) {
+        file.write_atomically(lock_contents(owner)?)?;
+    }
+
+    Ok(())
+}
+
+fn locked_error(file: &File, lock: &JsonValue, age: u64) -> Box<Error> {
+    Box::new(Error::new(ErrorKind::WouldBlock, format!(
+        "the state is locked by {} since {} seconds ago: {}, use --force-unlock if the lock is stale",
+        lock["owner"].as_str().unwrap_or("unknown"), age, file.path()
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blocking_thread_pool::BlockingThreadPool;
+    use crate::test_utils::TempDir;
+
+    fn write_lock(file: &File, owner: &str, time: u64) {
+        file.write_atomically(object! { owner: owner, pid: 1, time: time }.pretty(2)).unwrap();
+    }
+
+    #[test]
+    fn acquire_and_release() {
+        let temp = TempDir::new("lock");
+        let file = temp.file("state.json.lock");
+        let lock = StateLock::new(file.clone());
+
+        let guard = lock.acquire(0).unwrap();
+        assert_eq!(read_lock(&file).unwrap()["owner"].as_str(), Some(&lock.owner[..]));
+        assert!(lock.acquire(0).is_err());
+
+        guard.release().unwrap();
+        assert!(!file.exists());
+
+        // drop的时候也会释放
+        let guard = lock.acquire(60).unwrap();
+        drop(guard);
+        assert!(!file.exists());
+    }
+
+    #[test]
+    fn fresh_lock_is_not_taken_over() {
+        let temp = TempDir::new("lock");
+        let file = temp.file("state.json.lock");
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+        write_lock(&file, "1@other", now);
+
+        let error = StateLock::new(file.clone()).acquire(60).err().unwrap();
+        assert!(error.to_string().contains("1@other"));
+        assert_eq!(read_lock(&file).unwrap()["owner"], "1@other");
+    }
+
+    #[test]
+    fn stale_lock_is_taken_over() {
+        let temp = TempDir::new("lock");
+        let file = temp.file("state.json.lock");
+        write_lock(&file, "1@other", 1);
+
+        // 为0时锁永不过期
+        assert!(StateLock::new(file.clone()).acquire(0).is_err());
+
+        let lock = StateLock::new(file.clone());
+        let guard = lock.acquire(60).unwrap();
+        assert_eq!(read_lock(&file).unwrap()["owner"].as_str(), Some(&lock.owner[..]));
+        assert!(!File::new(&format!("{}.stale-{}", file.path(), process::id())).exists());
+
+        drop(guard);
+        assert!(!file.exists());
+    }
+
+    #[test]
+    fn replaced_lock_is_restored() {
+        // 发现过期的锁之后，锁被别人清理掉并重新创建了
+        let temp = TempDir::new("lock");
+        let file = temp.file("state.json.lock");
+        write_lock(&file, "2@other", 100);
+
+        let lock = StateLock::new(file.clone());
+        assert!(lock.remove_stale(&object! { owner: "1@other", time: 1 }).is_err());
+        assert_eq!(read_lock(&file).unwrap()["owner"], "2@other");
+
+        // 已经被别人清理掉了
+        file.rm().unwrap();
+        lock.remove_stale(&object! { owner: "1@other", time: 1 }).unwrap();
+        assert!(!file.exists());
+    }
+
+    #[test]
+    fn lock_of_another_owner_is_kept() {
+        let temp = TempDir::new("lock");
+        let file = temp.file("state.json.lock");
+        let guard = StateLock::new(file.clone()).acquire(0).unwrap();
+
+        // 锁在持有期间被别人当作过期的锁接管了
+        write_lock(&file, "2@other", 100);
+        guard.release().unwrap();
+        assert_eq!(read_lock(&file).unwrap()["owner"], "2@other");
+
+        StateLock::new(file.clone()).force_unlock().unwrap();
+        assert!(!file.exists());
+    }
+
+    #[test]
+    fn held_lock_is_refreshed() {
+        let temp = TempDir::new("lock");
+        let file = temp.file("state.json.lock");
+        let lock = StateLock::new(file.clone());
+        let guard = lock.acquire(0).unwrap();
+        let mut contents = read_lock(&file).unwrap();
+        contents["time"] = 1.into();
+        file.write_atomically(contents.pretty(2)).unwrap();
+
+        refresh_lock(&file, &lock.owner).unwrap();
+        assert!(read_lock(&file).unwrap()["time"].as_u64().unwrap() > 1);
+
+        // 不属于自己的锁不会被刷新
+        write_lock(&file, "2@other", 1);
+        refresh_lock(&file, &lock.owner).unwrap();
+        assert_eq!(read_lock(&file).unwrap()["time"], 1);
+
+        drop(guard);
+        assert!(file.exists());
+    }
+
+    #[test]
+    fn lock_is_released_after_worker_panic() {
+        let temp = TempDir::new("lock");
+        let file = temp.file("state.json.lock");
+        let guard = StateLock::new(file.clone()).acquire(60).unwrap();
+
+        let run = || -> AppResult<()> {
+            let mut pool = BlockingThreadPool::new(2);
+            pool.execute(|| panic!("worker panicked"));
+            pool.execute(|| Ok(()));
+            if let Err(e) = pool.close_and_wait() {
+                return Err(e);
+            }
+            Ok(())
+        };
+
+        // 工作线程的panic作为错误返回到主线程，主线程正常释放锁
+        assert!(run().is_err());
+        drop(guard);
+        assert!(!file.exists());
+    }
+
+    #[test]
+    fn unparsable_lock_is_not_stale_until_old() {
+        let temp = TempDir::new("lock");
+        let file = temp.file("state.json.lock");
+        file.write_atomically("").unwrap();
+
+        // 刚刚创建、还没有写入内容的锁不能被当作过期的锁接管
+        assert!(StateLock::new(file.clone()).acquire(60).is_err());
+        assert!(file.exists());
+
+        // 按照修改时间已经过期了
+        let old = SystemTime::now() - Duration::from_secs(120);
+        fs::File::options().write(true).open(file.get_raw()).unwrap().set_modified(old).unwrap();
+        let guard = StateLock::new(file.clone()).acquire(60).unwrap();
+        drop(guard);
+        assert!(!file.exists());
+    }
+}
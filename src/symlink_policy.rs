@@ -0,0 +1,137 @@
+use std::fs;
+use std::io::Error;
+use std::io::ErrorKind;
+use std::io::Result;
+use std::path::Path;
+
+use crate::file::File;
+
+/// 源目录里的符号链接的处理方式
+pub enum SymlinkPolicy {
+    /// 当作链接所指向的文件或者目录来处理，指向上级目录和源目录之外的链接会被跳过
+    Follow,
+    /// 和Follow一样，但是也会跟随指向源目录之外的链接
+    FollowExternal,
+    /// 忽略所有的符号链接
+    Skip,
+    /// 在状态里记录链接的目标，并使用upload-symlink命令来上传
+    Preserve,
+    /// 遇到符号链接时报错
+    Error,
+}
+
+/// 按照符号链接策略，一个本地文件在同步时被当作哪一种文件
+pub enum LocalKind {
+    File,
+    Dir,
+    /// 符号链接，以及链接的目标
+    Symlink(String),
+    /// 不存在，或者需要被忽略
+    None,
+}
+
+impl SymlinkPolicy {
+    pub fn parse(text: &str) -> Result<SymlinkPolicy> {
+        match text {
+            "follow" => Ok(SymlinkPolicy::Follow),
+            "follow-external" => Ok(SymlinkPolicy::FollowExternal),
+            "skip" => Ok(SymlinkPolicy::Skip),
+            "preserve" => Ok(SymlinkPolicy::Preserve),
+            "error" => Ok(SymlinkPolicy::Error),
+            _ => Err(Error::new(ErrorKind::InvalidInput, format!("unknown value of symlinks: {}", text))),
+        }
+    }
+
+    /// 判断一个本地文件应该被当作哪一种文件来同步
+    ///
+    /// root: 文件所在的源目录，follow时不会跟随指向源目录之外的链接
+    pub fn classify(&self, file: &File, root: &File) -> Result<LocalKind> {
+        let metadata = match fs::symlink_metadata(file.get_raw()) {
+            Ok(metadata) => metadata,
+            Err(_) => return Ok(LocalKind::None),
+        };
+
+        if !metadata.file_type().is_symlink() {
+            return Ok(if metadata.is_dir() {
+                LocalKind::Dir
+            } else if metadata.is_file() {
+                LocalKind::File
+            } else {
+                LocalKind::None
+            });
+        }
+
+        match self {
+            SymlinkPolicy::Skip => Ok(LocalKind::None),
+            SymlinkPolicy::Error => Err(Error::new(ErrorKind::InvalidData, format!("symbolic links are not allowed: {}", file.path()))),
+            SymlinkPolicy::Preserve => Ok(LocalKind::Symlink(fs::read_link(file.get_raw())?.to_string_lossy().into_owned())),
+            SymlinkPolicy::Follow | SymlinkPolicy::FollowExternal => {
+                // 跟随指向源目录之外的链接可能会把不应该公开的文件(比如/etc下的文件)上传出去
+                if let SymlinkPolicy::Follow = self {
+                    if !is_inside(file.get_raw(), root.get_raw()) {
+                        println!("跳过指向源目录之外的符号链接: {}", file.path());
+                        return Ok(LocalKind::None);
+                    }
+                }
+
+                if file.is_dir() {
+                    if is_loop(file.get_raw()) {
+                        println!("跳过指向上级目录的符号链接: {}", file.path());
+                        Ok(LocalKind::None)
+                    } else {
+                        Ok(LocalKind::Dir)
+                    }
+                } else if file.is_file() {
+                    Ok(LocalKind::File)
+                } else {
+                    // 链接的目标不存在
+                    Ok(LocalKind::None)
+                }
+            },
+        }
+    }
+}
+
+/// 判断一个路径在解析完所有的符号链接之后是否还在root里面，链接的目标不存在时返回true(会被当作不存在的文件跳过)
+fn is_inside(path: &Path, root: &Path) -> bool {
+    let target = match fs::canonicalize(path) {
+        Ok(target) => target,
+        Err(_) => return true,
+    };
+
+    fs::canonicalize(root).is_ok_and(|root| target.starts_with(root))
+}
+
+/// 判断一个目录是不是它自己路径上的某一个上级目录，继续递归进去的话会陷入死循环
+fn is_loop(dir: &Path) -> bool {
+    let id = match file_id(dir) {
+        Some(id) => id,
+        None => return false,
+    };
+
+    let mut parent = dir.parent();
+    while let Some(p) = parent {
+        if file_id(p).as_ref() == Some(&id) {
+            return true;
+        }
+        parent = p.parent();
+    }
+
+    false
+}
+
+/// 用设备号和inode来区分不同的目录
+#[cfg(unix)]
+fn file_id(path: &Path) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+
+    let path = if path.as_os_str().is_empty() { Path::new(".") } else { path };
+    fs::metadata(path).ok().map(|m| (m.dev(), m.ino()))
+}
+
+/// 没有inode的平台上使用规范化之后的路径来区分不同的目录
+#[cfg(not(unix))]
+fn file_id(path: &Path) -> Option<std::path::PathBuf> {
+    let path = if path.as_os_str().is_empty() { Path::new(".") } else { path };
+    fs::canonicalize(path).ok()
+}
\ No newline at end of file
@@ -0,0 +1,38 @@
+use std::env;
+use std::fs;
+use std::process;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+
+use crate::file::File;
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+
+/// 单元测试使用的临时目录，被drop时(包括断言失败的时候)连同里面的所有文件一起删除
+pub struct TempDir {
+    pub dir: File,
+}
+
+impl TempDir {
+    pub fn new(name: &str) -> TempDir {
+        let id = NEXT_ID.fetch_add(1, Ordering::SeqCst);
+        let dir = File::from(env::temp_dir().join(format!("incremental-upload-test-{}-{}-{}", process::id(), id, name)));
+        if dir.exists() {
+            fs::remove_dir_all(dir.get_raw()).unwrap();
+        }
+        dir.mkdirs().unwrap();
+
+        TempDir { dir }
+    }
+
+    /// 临时目录里的一个文件
+    pub fn file(&self, name: &str) -> File {
+        self.dir.append(name).unwrap()
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(self.dir.get_raw());
+    }
+}
@@ -14,14 +14,18 @@ impl VariableReplace {
     }
 
     pub fn apply(&self, text: &str) -> String {
+        // 先替换长的变量名，避免$remote-path_被当成$remote-path后面跟着一个_
+        let mut keys: Vec<&String> = self.variables.keys().collect();
+        keys.sort_by(|a, b| b.len().cmp(&a.len()).then_with(|| a.cmp(b)));
+
         let mut result = text.to_owned();
         let mut replaced;
         for _i in 0..1000 {
             replaced = false;
     
-            for k in self.variables.keys() {
+            for k in &keys {
                 let pattern = "$".to_string() + k;
-                let new = result.replace(&pattern[..], &self.variables[k][..]);
+                let new = result.replace(&pattern[..], &self.variables[*k][..]);
                 replaced |= result != new;
                 result = new;
             }
@@ -38,4 +42,32 @@ impl Clone for VariableReplace {
     fn clone(&self) -> Self {
         Self { variables: self.variables.clone() }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn longer_names_are_replaced_first() {
+        // HashMap的遍历顺序不固定，多试几次
+        for _ in 0..20 {
+            let mut vars = VariableReplace::new();
+            vars.add("path", "a/b.txt");
+            vars.add("path_", "a_b.txt");
+            vars.add("remote-path", "/r/a/b.txt");
+            vars.add("remote-path_", "/r/a_b.txt");
+            vars.add("remote", "host");
+
+            assert_eq!(vars.apply("$remote-path_ $remote-path $path_ $path $remote"), "/r/a_b.txt /r/a/b.txt a_b.txt a/b.txt host");
+        }
+    }
+
+    #[test]
+    fn nested_variables() {
+        let mut vars = VariableReplace::new();
+        vars.add("name", "b.txt");
+        vars.add("path", "a/$name");
+        assert_eq!(vars.apply("cp $path $path.bak"), "cp a/b.txt a/b.txt.bak");
+    }
+}